@@ -1,6 +1,7 @@
+use std::mem::size_of;
 use std::ptr::null;
 
-use crate::z_malloc::{z_free, z_malloc_of_type};
+use crate::z_malloc::{z_free, z_malloc_of_type, z_malloc_oom, z_try_malloc_of_type, AllocError};
 
 pub struct Node<T: Copy + PartialEq> {
     prev: *const Node<T>,
@@ -31,21 +32,22 @@ impl<T: Copy + PartialEq> List<T> {
     // same as
     // list *listCreate(void)
     pub fn new() -> Self {
-        let list = Self {
+        Self {
             head: null(),
             tail: null(),
             len: 0,
             value_clone: None,
             value_drop: None,
             value_equals: None,
-        };
-
-        list
+        }
     }
 
     // same as
     // void listEmpty(list *list)
     // unsafe cuz free_value
+    /// # Safety
+    ///
+    /// The value drop method, if set, is called on every value.
     pub unsafe fn clear(&mut self) {
         let len = self.len;
         let mut current = self.head;
@@ -64,9 +66,15 @@ impl<T: Copy + PartialEq> List<T> {
     }
 
     pub fn push_front(&mut self, value: T) -> &mut Self {
-        let node = unsafe { z_malloc_of_type::<Node<T>>() as *mut Node<T> };
+        self.try_push_front(value).unwrap_or_else(|e| z_malloc_oom(e.size()))
+    }
+
+    /// Same as `push_front` but returns an error instead of calling the oom
+    /// handler when the node allocation fails. The list is untouched on error.
+    pub fn try_push_front(&mut self, value: T) -> Result<&mut Self, AllocError> {
+        let node = unsafe { z_try_malloc_of_type::<Node<T>>() as *mut Node<T> };
         if node.is_null() {
-            panic!("z_malloc_of_type fail");
+            return Err(AllocError::new(size_of::<Node<T>>()));
         }
 
         let node = unsafe { &mut *node };
@@ -83,13 +91,19 @@ impl<T: Copy + PartialEq> List<T> {
             self.head = node;
         }
         self.len += 1;
-        self
+        Ok(self)
     }
 
     pub fn push_back(&mut self, value: T) -> &mut Self {
-        let node = unsafe { z_malloc_of_type::<Node<T>>() as *mut Node<T> };
+        self.try_push_back(value).unwrap_or_else(|e| z_malloc_oom(e.size()))
+    }
+
+    /// Same as `push_back` but returns an error instead of calling the oom
+    /// handler when the node allocation fails. The list is untouched on error.
+    pub fn try_push_back(&mut self, value: T) -> Result<&mut Self, AllocError> {
+        let node = unsafe { z_try_malloc_of_type::<Node<T>>() as *mut Node<T> };
         if node.is_null() {
-            panic!("z_malloc_of_type fail");
+            return Err(AllocError::new(size_of::<Node<T>>()));
         }
 
         let node = unsafe { &mut *node };
@@ -106,18 +120,17 @@ impl<T: Copy + PartialEq> List<T> {
             self.tail = node;
         }
         self.len += 1;
-        self
+        Ok(self)
     }
 
     // same as
     // list *listInsertNode(list *list, listNode *old_node, void *value, int after)
     // unsafe cuz exist_node may be not belong the list
+    /// # Safety
+    ///
+    /// `exist_node` must be a live node of this list.
     pub unsafe fn insert_node(&mut self, exist_node: *mut Node<T>, value: T, after: bool) -> &mut Self {
         let node = z_malloc_of_type::<Node<T>>() as *mut Node<T>;
-        if node.is_null() {
-            panic!("z_malloc_of_type fail");
-        }
-
         let exist_node = &mut *exist_node;
         let node = &mut *node;
         node.value = value;
@@ -148,7 +161,19 @@ impl<T: Copy + PartialEq> List<T> {
 
     // same as
     // void listDelNode(list *list, listNode *node)
+    /// # Safety
+    ///
+    /// `node` must be a live node of this list, it is freed on return.
     pub unsafe fn remove(&mut self, node: *mut Node<T>) {
+        self.unlink(node);
+        if let Some(value_drop) = self.value_drop {
+            value_drop((*node).value);
+        }
+        z_free(node as *const u8);
+    }
+
+    // takes node out of the list, the caller frees it
+    unsafe fn unlink(&mut self, node: *mut Node<T>) {
         let node = &mut *node;
         // if prev is null, it is the head node
         if node.prev.is_null() {
@@ -163,36 +188,92 @@ impl<T: Copy + PartialEq> List<T> {
         } else {
             (*(node.next as *mut Node<T>)).prev = node.prev;
         }
+        self.len -= 1;
+    }
 
-        if let Some(value_drop) = self.value_drop {
-            value_drop(node.value);
+    // takes the value out of node and frees it, the value drop method is not
+    // called as the value goes to the caller
+    unsafe fn pop_node(&mut self, node: *const Node<T>) -> Option<T> {
+        if node.is_null() {
+            return None;
         }
-
-        z_free(node as *mut Node<T> as *const u8);
-        self.len -= 1;
+        let node = node as *mut Node<T>;
+        self.unlink(node);
+        let value = (*node).value;
+        z_free(node as *const u8);
+        Some(value)
     }
 
     // same as
     // listNode *listIndex(list *list, long index)
-    pub fn get(&self, index: isize) -> *const Node<T> {
+    // negative indexes count from the tail, -1 being the last node, null if
+    // out of range
+    pub fn index(&self, index: isize) -> *const Node<T> {
         let mut n: *const Node<T>;
-        let mut index = index;
+        let mut steps;
         if index < 0 {
-            index = (-index) - 1;
+            steps = index.unsigned_abs() - 1;
             n = self.tail;
-            while index > 0 && !n.is_null() {
+            while steps > 0 && !n.is_null() {
                 unsafe { n = (*n).prev; }
+                steps -= 1;
             }
         } else {
+            steps = index as usize;
             n = self.head;
-            while index > 0 && !n.is_null() {
+            while steps > 0 && !n.is_null() {
                 unsafe { n = (*n).next; }
+                steps -= 1;
             }
         }
 
         n
     }
 
+    /// The value at `index`, negative indexes counting from the back like
+    /// LINDEX, -1 being the last value. None if out of range.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::List;
+    ///
+    /// let mut list = List::new();
+    /// list.push_back(1).push_back(2).push_back(3);
+    /// assert_eq!(list.get(1), Some(&2));
+    /// assert_eq!(list.get(-1), Some(&3));
+    /// assert_eq!(list.get(3), None);
+    /// ```
+    pub fn get(&self, index: isize) -> Option<&T> {
+        unsafe { self.index(index).as_ref().map(|n| &n.value) }
+    }
+
+    /// `get` for changing the value in place.
+    pub fn get_mut(&mut self, index: isize) -> Option<&mut T> {
+        unsafe { (self.index(index) as *mut Node<T>).as_mut().map(|n| &mut n.value) }
+    }
+
+    /// The first value, None if the list is empty.
+    pub fn front(&self) -> Option<&T> {
+        unsafe { self.head.as_ref().map(|n| &n.value) }
+    }
+
+    /// The last value, None if the list is empty.
+    pub fn back(&self) -> Option<&T> {
+        unsafe { self.tail.as_ref().map(|n| &n.value) }
+    }
+
+    /// Removes the first value and returns it, None if the list is empty.
+    /// The value drop method is not called, the value is the caller's now.
+    pub fn pop_front(&mut self) -> Option<T> {
+        unsafe { self.pop_node(self.head) }
+    }
+
+    /// `pop_front` from the back.
+    pub fn pop_back(&mut self) -> Option<T> {
+        unsafe { self.pop_node(self.tail) }
+    }
+
     // move the tail node to head, same as
     // void listRotateTailToHead(list *list)
     pub fn move_tail_to_head(&mut self) {
@@ -275,6 +356,17 @@ impl<T: Copy + PartialEq> List<T> {
         null()
     }
 
+    /// Position of the first value equal to `value`, compared with the value
+    /// equals method if set, like `search`.
+    pub fn find(&self, value: &T) -> Option<usize> {
+        self.iter().position(|n| unsafe {
+            match self.value_equals {
+                Some(value_equals) => value_equals((*n).value, *value),
+                None => (*n).value == *value,
+            }
+        })
+    }
+
     pub fn set_value_clone_method(&mut self, value_clone: Option<fn(T)->T>) {
         self.value_clone = value_clone;
     }
@@ -312,6 +404,12 @@ impl<T: Copy + PartialEq> List<T> {
     }
 }
 
+impl<T: Copy + PartialEq> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Copy + PartialEq> Drop for List<T> {
     // same as
     // void listRelease(list *list)
@@ -369,3 +467,37 @@ impl<T: Copy + PartialEq> Iterator for It<T> {
         Some(current)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::z_malloc::{z_malloc_fail_nth, OomHandlerGuard};
+
+    fn panic_oom(size: usize) -> ! {
+        panic!("oom {}", size)
+    }
+
+    #[test]
+    fn test_insert_node_fault() {
+        let mut list = List::new();
+        list.push_back(1).push_back(3);
+
+        z_malloc_fail_nth(1);
+        assert!(list.try_push_front(0).is_err());
+
+        let _oom = OomHandlerGuard::new(panic_oom);
+        z_malloc_fail_nth(1);
+        let first = list.first() as *mut Node<i32>;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+            list.insert_node(first, 2, true);
+        }));
+        assert!(result.is_err());
+
+        let elements: Vec<_> = list.iter().map(|n| unsafe { (*n).value }).collect();
+        assert_eq!(elements.as_slice(), &[1, 3]);
+
+        unsafe { list.insert_node(first, 2, true); }
+        let elements: Vec<_> = list.iter().map(|n| unsafe { (*n).value }).collect();
+        assert_eq!(elements.as_slice(), &[1, 2, 3]);
+    }
+}
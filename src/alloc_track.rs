@@ -0,0 +1,262 @@
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+
+/// A block handed out by z_malloc and not freed yet.
+#[derive(Clone, Debug)]
+pub struct LiveAllocation {
+    /// address of the block
+    pub ptr: usize,
+    /// usable size of the block
+    pub size: usize,
+    /// allocation sequence number, grows with every allocation
+    pub seq: u64,
+    /// thread the block was allocated on
+    pub thread: ThreadId,
+    /// where the block was allocated, if `z_malloc_track_backtrace` is on
+    pub backtrace: Option<Arc<Backtrace>>,
+}
+
+impl Display for LiveAllocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#{} {:#x} {} bytes on {:?}", self.seq, self.ptr, self.size, self.thread)?;
+        if let Some(backtrace) = &self.backtrace {
+            write!(f, "\n{}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+struct Record {
+    size: usize,
+    seq: u64,
+    thread: ThreadId,
+    backtrace: Option<Arc<Backtrace>>,
+}
+
+static LIVE: Mutex<BTreeMap<usize, Record>> = Mutex::new(BTreeMap::new());
+static SEQ: AtomicU64 = AtomicU64::new(0);
+static BACKTRACE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // set while the tracker itself runs, its own allocations are not tracked
+    // so that it works under ZMalloc as the global allocator
+    static IN_TRACKER: Cell<bool> = const { Cell::new(false) };
+}
+
+// run f unless the tracker is already running on this thread
+fn untracked<F: FnOnce()>(f: F) {
+    IN_TRACKER.with(|busy| {
+        if busy.replace(true) {
+            return;
+        }
+        f();
+        busy.set(false);
+    });
+}
+
+pub(crate) fn track_alloc(ptr: *const u8, size: usize) {
+    untracked(|| {
+        let backtrace = if BACKTRACE.load(Ordering::Relaxed) {
+            Some(Arc::new(Backtrace::force_capture()))
+        } else {
+            None
+        };
+        let record = Record {
+            size,
+            seq: SEQ.fetch_add(1, Ordering::Relaxed),
+            thread: std::thread::current().id(),
+            backtrace,
+        };
+        LIVE.lock().unwrap_or_else(|e| e.into_inner()).insert(ptr as usize, record);
+    });
+}
+
+pub(crate) fn track_free(ptr: *const u8) {
+    untracked(|| {
+        LIVE.lock().unwrap_or_else(|e| e.into_inner()).remove(&(ptr as usize));
+    });
+}
+
+// the record of a block under realloc, out of LIVE while the allocator may
+// hand its address to another thread
+pub(crate) struct TakenRecord(Record);
+
+// removes the record of ptr before it is reallocated
+pub(crate) fn track_take(ptr: *const u8) -> Option<TakenRecord> {
+    let mut taken = None;
+    untracked(|| {
+        taken = LIVE.lock().unwrap_or_else(|e| e.into_inner()).remove(&(ptr as usize)).map(TakenRecord);
+    });
+    taken
+}
+
+// puts the record of ptr back when its realloc failed and the block stays
+pub(crate) fn track_restore(ptr: *const u8, taken: Option<TakenRecord>) {
+    if let Some(TakenRecord(record)) = taken {
+        untracked(|| {
+            LIVE.lock().unwrap_or_else(|e| e.into_inner()).insert(ptr as usize, record);
+        });
+    }
+}
+
+/// Captures a backtrace for every allocation from now on when `enable`,
+/// slow but tells where a leaked block comes from.
+pub fn z_malloc_track_backtrace(enable: bool) {
+    BACKTRACE.store(enable, Ordering::Relaxed);
+}
+
+/// All blocks allocated by z_malloc and not freed yet, oldest first.
+pub fn z_malloc_live_allocations() -> Vec<LiveAllocation> {
+    let mut live = Vec::new();
+    untracked(|| {
+        live = LIVE.lock().unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(&ptr, record)| LiveAllocation {
+                ptr,
+                size: record.size,
+                seq: record.seq,
+                thread: record.thread,
+                backtrace: record.backtrace.clone(),
+            })
+            .collect();
+    });
+    live.sort_by_key(|a| a.seq);
+    live
+}
+
+/// Writes every live allocation to stderr.
+pub fn z_malloc_dump_live_allocations() {
+    let live = z_malloc_live_allocations();
+    eprintln!("z_malloc: {} live allocations", live.len());
+    for a in live {
+        eprintln!("{}", a);
+    }
+}
+
+/// Remembers where the current thread stands, to find the blocks it
+/// allocated afterwards and never freed.
+///
+/// # Examples
+///
+/// ```
+/// # #[cfg(feature = "alloc-track")] {
+/// use redis_rust_copy::{LeakCheck, Sds};
+///
+/// let check = LeakCheck::new();
+/// let s = Sds::from_str("no leak");
+/// drop(s);
+/// check.assert_no_leaks();
+/// # }
+/// ```
+pub struct LeakCheck {
+    seq: u64,
+    thread: ThreadId,
+}
+
+impl LeakCheck {
+    pub fn new() -> Self {
+        Self {
+            seq: SEQ.load(Ordering::Relaxed),
+            thread: std::thread::current().id(),
+        }
+    }
+
+    /// blocks allocated by this thread since `new` and still alive
+    pub fn leaks(&self) -> Vec<LiveAllocation> {
+        z_malloc_live_allocations()
+            .into_iter()
+            .filter(|a| a.seq >= self.seq && a.thread == self.thread)
+            .collect()
+    }
+
+    /// Panics listing the leaked blocks, if any.
+    pub fn assert_no_leaks(&self) {
+        let leaks = self.leaks();
+        if !leaks.is_empty() {
+            let list: Vec<String> = leaks.iter().map(|a| a.to_string()).collect();
+            panic!("{} leaked allocations:\n{}", leaks.len(), list.join("\n"));
+        }
+    }
+}
+
+impl Default for LeakCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::z_malloc::{z_free, z_malloc_usable, z_realloc_usable, z_try_realloc_usable, GLOBAL_STATE_LOCK};
+    use crate::{IntSet, List, Sds};
+
+    #[test]
+    fn test_leak_check() {
+        let check = LeakCheck::new();
+        let (p, usable) = z_malloc_usable(10);
+        let leaks = check.leaks();
+        assert_eq!(leaks.len(), 1);
+        assert_eq!(leaks[0].ptr, p as usize);
+        assert_eq!(leaks[0].size, usable);
+
+        let (p, usable) = unsafe { z_realloc_usable(p, 1000) };
+        let leaks = check.leaks();
+        assert_eq!(leaks.len(), 1);
+        assert_eq!(leaks[0].ptr, p as usize);
+        assert_eq!(leaks[0].size, usable);
+
+        // a failed realloc keeps the block and its record as they were
+        let seq = leaks[0].seq;
+        let (q, _) = unsafe { z_try_realloc_usable(p, 1 << 60) };
+        assert!(q.is_null());
+        let leaks = check.leaks();
+        assert_eq!(leaks.len(), 1);
+        assert_eq!((leaks[0].ptr, leaks[0].size, leaks[0].seq), (p as usize, usable, seq));
+
+        unsafe { z_free(p); }
+        check.assert_no_leaks();
+    }
+
+    #[test]
+    fn test_no_leaks() {
+        let check = LeakCheck::new();
+        {
+            let mut s = Sds::from_str("clone from");
+            s.clone_from(&Sds::from_slice(&[1u8; 300]));
+            s.push_str("grow");
+
+            let mut list = List::new();
+            list.push_back(1).push_front(0);
+            let mut other = list.clone();
+            other.clone_from(&list);
+            unsafe { list.clear(); }
+            list.append(&mut other);
+
+            let mut set = IntSet::new();
+            set.insert(1);
+            set.insert(i64::MAX);
+            set.remove(1);
+        }
+        check.assert_no_leaks();
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let _lock = GLOBAL_STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let check = LeakCheck::new();
+        z_malloc_track_backtrace(true);
+        let (p, _) = z_malloc_usable(10);
+        z_malloc_track_backtrace(false);
+        let leaks = check.leaks();
+        assert!(leaks[0].backtrace.is_some());
+        assert!(std::panic::catch_unwind(|| check.assert_no_leaks()).is_err());
+        unsafe { z_free(p); }
+        check.assert_no_leaks();
+    }
+}
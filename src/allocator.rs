@@ -0,0 +1,452 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ptr::null;
+use std::sync::Mutex;
+
+/// A memory allocator the z_malloc functions can be built on, like the
+/// libc / jemalloc / tcmalloc choice of redis.
+///
+/// The backend is picked at build time by cargo feature, see `Backend`.
+/// Every backend returns blocks aligned to at least `MALLOC_MIN_ALIGN`.
+pub trait ZAllocator {
+    /// name of the allocator, same as ZMALLOC_LIB
+    const NAME: &'static str;
+
+    /// if `usable_size` works, same as HAVE_MALLOC_SIZE. Without it z_malloc
+    /// keeps the size of every block in a PREFIX_SIZE header.
+    const HAVE_MALLOC_SIZE: bool = true;
+
+    /// # Safety
+    ///
+    /// The returned block is uninitialized and must be released by `free` of the same backend.
+    unsafe fn malloc(size: usize) -> *const u8;
+
+    /// # Safety
+    ///
+    /// `ptr` must be null or come from this backend and not be freed yet.
+    unsafe fn realloc(ptr: *const u8, size: usize) -> *const u8;
+
+    /// # Safety
+    ///
+    /// `ptr` must be null or come from this backend and not be freed yet.
+    unsafe fn free(ptr: *const u8);
+
+    /// # Safety
+    ///
+    /// `ptr` must come from this backend and not be freed yet.
+    unsafe fn usable_size(ptr: *const u8) -> usize;
+
+    /// Gives free pages back to the system where the allocator supports it.
+    /// Returns if any memory was released.
+    fn purge() -> bool {
+        false
+    }
+}
+
+// blocks of every backend are aligned for any fundamental type
+#[cfg(target_pointer_width = "64")]
+pub(crate) const MALLOC_MIN_ALIGN: usize = 16;
+#[cfg(not(target_pointer_width = "64"))]
+pub(crate) const MALLOC_MIN_ALIGN: usize = 8;
+
+// same as
+// #if defined(USE_TCMALLOC) ... #elif defined(USE_JEMALLOC) ... #endif
+// the first enabled feature of size-class-malloc, system-malloc wins, libc otherwise
+#[cfg(feature = "size-class-malloc")]
+pub(crate) type Backend = SizeClassAllocator;
+#[cfg(all(feature = "system-malloc", not(feature = "size-class-malloc")))]
+pub(crate) type Backend = SystemAllocator;
+#[cfg(not(any(feature = "system-malloc", feature = "size-class-malloc")))]
+pub(crate) type Backend = LibcAllocator;
+
+extern "C" {
+    fn malloc(size: usize) -> *const u8;
+    fn free(ptr: *const u8);
+    fn realloc(ptr: *const u8, size: usize) -> *const u8;
+    fn posix_memalign(ptr: *mut *const u8, align: usize, size: usize) -> i32;
+}
+
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn malloc_size(ptr: *const u8) -> usize;
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn malloc_usable_size(ptr: *const u8) -> usize;
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+extern "C" {
+    fn malloc_trim(pad: usize) -> i32;
+}
+
+// release the free memory at the top of the heap and in every arena of malloc
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn libc_trim() -> bool {
+    unsafe { malloc_trim(0) != 0 }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn libc_trim() -> bool {
+    false
+}
+
+/// malloc of the C library.
+pub struct LibcAllocator;
+
+impl ZAllocator for LibcAllocator {
+    const NAME: &'static str = "libc";
+
+    #[inline]
+    unsafe fn malloc(size: usize) -> *const u8 {
+        malloc(size)
+    }
+
+    #[inline]
+    unsafe fn realloc(ptr: *const u8, size: usize) -> *const u8 {
+        realloc(ptr, size)
+    }
+
+    #[inline]
+    unsafe fn free(ptr: *const u8) {
+        free(ptr)
+    }
+
+    fn purge() -> bool {
+        libc_trim()
+    }
+
+    #[cfg(target_os = "macos")]
+    #[inline]
+    unsafe fn usable_size(ptr: *const u8) -> usize {
+        malloc_size(ptr)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline]
+    unsafe fn usable_size(ptr: *const u8) -> usize {
+        malloc_usable_size(ptr)
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    const HAVE_MALLOC_SIZE: bool = false;
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    unsafe fn usable_size(_ptr: *const u8) -> usize {
+        unreachable!("no malloc size on this target, z_malloc uses PREFIX_SIZE")
+    }
+}
+
+/// Rust's `System` allocator.
+///
+/// `System` needs the layout back on free, so every block carries its size
+/// in a `MALLOC_MIN_ALIGN` bytes header.
+pub struct SystemAllocator;
+
+impl SystemAllocator {
+    const HDR_SIZE: usize = MALLOC_MIN_ALIGN;
+
+    // None if size and the header overflow or go above isize::MAX
+    #[inline]
+    fn layout(size: usize) -> Option<Layout> {
+        let size = size.checked_add(Self::HDR_SIZE)?;
+        Layout::from_size_align(size, MALLOC_MIN_ALIGN).ok()
+    }
+
+    // layout of a live block, its size was checked by layout when allocated
+    #[inline]
+    unsafe fn block_layout(p: *const u8) -> Layout {
+        Layout::from_size_align_unchecked(*(p as *const usize) + Self::HDR_SIZE, MALLOC_MIN_ALIGN)
+    }
+}
+
+impl ZAllocator for SystemAllocator {
+    const NAME: &'static str = "system";
+
+    unsafe fn malloc(size: usize) -> *const u8 {
+        let layout = match Self::layout(size) {
+            Some(layout) => layout,
+            None => return null(),
+        };
+        let p = System.alloc(layout);
+        if p.is_null() {
+            return null();
+        }
+        *(p as *mut usize) = size;
+        p.add(Self::HDR_SIZE)
+    }
+
+    unsafe fn realloc(ptr: *const u8, size: usize) -> *const u8 {
+        if ptr.is_null() {
+            return Self::malloc(size);
+        }
+        let new_layout = match Self::layout(size) {
+            Some(layout) => layout,
+            None => return null(),
+        };
+        let p = ptr.sub(Self::HDR_SIZE) as *mut u8;
+        let p = System.realloc(p, Self::block_layout(p), new_layout.size());
+        if p.is_null() {
+            return null();
+        }
+        *(p as *mut usize) = size;
+        p.add(Self::HDR_SIZE)
+    }
+
+    unsafe fn free(ptr: *const u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let p = ptr.sub(Self::HDR_SIZE) as *mut u8;
+        System.dealloc(p, Self::block_layout(p));
+    }
+
+    #[inline]
+    unsafe fn usable_size(ptr: *const u8) -> usize {
+        *(ptr.sub(Self::HDR_SIZE) as *const usize)
+    }
+
+    // System sits on malloc on unix
+    #[cfg(unix)]
+    fn purge() -> bool {
+        libc_trim()
+    }
+}
+
+/// A jemalloc style allocator rounding small requests up to size classes.
+///
+/// Small blocks are carved out of `CHUNK_SIZE` aligned slabs holding a single
+/// class, and recycled through a free list per class. Bigger requests get a
+/// chunk of their own. Either way the chunk header found by masking the block
+/// pointer tells the usable size. Slabs are never given back to the system.
+///
+/// The masking needs every chunk, large ones included, aligned to
+/// `CHUNK_SIZE`. Each request above 8K is then a `posix_memalign` with 64K
+/// alignment: a 9K block costs a 64K aligned allocation, and malloc may
+/// waste up to 64K of padding to find the alignment. Workloads with many
+/// mid-sized blocks are better served by the libc backend.
+pub struct SizeClassAllocator;
+
+// same as the small size classes of jemalloc with 16 bytes quantum:
+// 16 bytes spacing up to 128, then 4 classes per doubling
+const SIZE_CLASSES: [usize; 32] = [
+    16, 32, 48, 64, 80, 96, 112, 128,
+    160, 192, 224, 256,
+    320, 384, 448, 512,
+    640, 768, 896, 1024,
+    1280, 1536, 1792, 2048,
+    2560, 3072, 3584, 4096,
+    5120, 6144, 7168, 8192,
+];
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHUNK_HDR_SIZE: usize = 16;
+const LARGE_CLASS: usize = usize::MAX;
+
+#[repr(C)]
+struct ChunkHdr {
+    // index of SIZE_CLASSES, or LARGE_CLASS
+    class: usize,
+    // usable size of a large chunk
+    size: usize,
+}
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct Bins([*mut FreeBlock; SIZE_CLASSES.len()]);
+
+// the free blocks are only reached with the lock held
+unsafe impl Send for Bins {}
+
+static BINS: Mutex<Bins> = Mutex::new(Bins([std::ptr::null_mut(); SIZE_CLASSES.len()]));
+
+impl SizeClassAllocator {
+    #[inline]
+    fn class_of(size: usize) -> Option<usize> {
+        let class = SIZE_CLASSES.partition_point(|&c| c < size);
+        if class < SIZE_CLASSES.len() {
+            Some(class)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    unsafe fn chunk_hdr(ptr: *const u8) -> *mut ChunkHdr {
+        (ptr as usize & !(CHUNK_SIZE - 1)) as *mut ChunkHdr
+    }
+
+    // CHUNK_SIZE aligned so chunk_hdr finds the header, even for a large
+    // block of a single chunk, see the cost in the SizeClassAllocator doc
+    unsafe fn chunk(size: usize) -> *mut ChunkHdr {
+        let mut p = null();
+        if posix_memalign(&mut p, CHUNK_SIZE, size) != 0 {
+            return std::ptr::null_mut();
+        }
+        p as *mut ChunkHdr
+    }
+
+    unsafe fn malloc_small(class: usize) -> *const u8 {
+        let mut bins = BINS.lock().unwrap_or_else(|e| e.into_inner());
+        if bins.0[class].is_null() {
+            let hdr = Self::chunk(CHUNK_SIZE);
+            if hdr.is_null() {
+                return null();
+            }
+            (*hdr).class = class;
+            (*hdr).size = 0;
+
+            let block_size = SIZE_CLASSES[class];
+            let mut offset = CHUNK_HDR_SIZE;
+            while offset + block_size <= CHUNK_SIZE {
+                let block = (hdr as *mut u8).add(offset) as *mut FreeBlock;
+                (*block).next = bins.0[class];
+                bins.0[class] = block;
+                offset += block_size;
+            }
+        }
+
+        let block = bins.0[class];
+        bins.0[class] = (*block).next;
+        block as *const u8
+    }
+
+    unsafe fn malloc_large(size: usize) -> *const u8 {
+        let total = match size.checked_add(CHUNK_HDR_SIZE) {
+            Some(total) if total <= isize::MAX as usize => total,
+            _ => return null(),
+        };
+        let hdr = Self::chunk(total);
+        if hdr.is_null() {
+            return null();
+        }
+        (*hdr).class = LARGE_CLASS;
+        (*hdr).size = size;
+        (hdr as *const u8).add(CHUNK_HDR_SIZE)
+    }
+}
+
+impl ZAllocator for SizeClassAllocator {
+    const NAME: &'static str = "size-class";
+
+    unsafe fn malloc(size: usize) -> *const u8 {
+        match Self::class_of(size) {
+            Some(class) => Self::malloc_small(class),
+            None => Self::malloc_large(size),
+        }
+    }
+
+    unsafe fn realloc(ptr: *const u8, size: usize) -> *const u8 {
+        if ptr.is_null() {
+            return Self::malloc(size);
+        }
+
+        let old_size = Self::usable_size(ptr);
+        // still the same class, nothing to move
+        if Self::class_of(old_size) == Self::class_of(size) && size <= old_size {
+            return ptr;
+        }
+
+        let new_ptr = Self::malloc(size);
+        if !new_ptr.is_null() {
+            ptr.copy_to_nonoverlapping(new_ptr as *mut u8, old_size.min(size));
+            Self::free(ptr);
+        }
+        new_ptr
+    }
+
+    unsafe fn free(ptr: *const u8) {
+        if ptr.is_null() {
+            return;
+        }
+
+        let hdr = Self::chunk_hdr(ptr);
+        let class = (*hdr).class;
+        if class == LARGE_CLASS {
+            free(hdr as *const u8);
+        } else {
+            let mut bins = BINS.lock().unwrap_or_else(|e| e.into_inner());
+            let block = ptr as *mut FreeBlock;
+            (*block).next = bins.0[class];
+            bins.0[class] = block;
+        }
+    }
+
+    // slabs are kept, but freed large chunks sit in malloc
+    fn purge() -> bool {
+        libc_trim()
+    }
+
+    #[inline]
+    unsafe fn usable_size(ptr: *const u8) -> usize {
+        let hdr = Self::chunk_hdr(ptr);
+        match (*hdr).class {
+            LARGE_CLASS => (*hdr).size,
+            class => SIZE_CLASSES[class],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    unsafe fn check_backend<A: ZAllocator>() {
+        for &size in &[1usize, 15, 16, 17, 100, 4000, 8192, 8193, 200_000] {
+            let p = A::malloc(size) as *mut u8;
+            assert!(!p.is_null(), "{} malloc {}", A::NAME, size);
+            assert_eq!(p as usize % MALLOC_MIN_ALIGN, 0, "{} align {}", A::NAME, size);
+            assert!(!A::HAVE_MALLOC_SIZE || A::usable_size(p) >= size, "{} usable {}", A::NAME, size);
+            p.write_bytes(0xab, size);
+
+            let p = A::realloc(p, size * 3) as *mut u8;
+            assert!(!p.is_null(), "{} realloc {}", A::NAME, size * 3);
+            assert!(!A::HAVE_MALLOC_SIZE || A::usable_size(p) >= size * 3);
+            assert!((0..size).all(|i| *p.add(i) == 0xab), "{} realloc keeps data", A::NAME);
+
+            let p = A::realloc(p, 1) as *mut u8;
+            assert_eq!(*p, 0xab);
+            A::free(p);
+        }
+        A::free(null());
+
+        // sizes that overflow with the header or go above isize::MAX
+        for &size in &[usize::MAX, usize::MAX - 20, isize::MAX as usize, isize::MAX as usize + 1] {
+            assert!(A::malloc(size).is_null(), "{} malloc {}", A::NAME, size);
+            let p = A::malloc(100);
+            assert!(A::realloc(p, size).is_null(), "{} realloc {}", A::NAME, size);
+            A::free(p);
+        }
+    }
+
+    #[test]
+    fn test_backends() {
+        unsafe {
+            check_backend::<LibcAllocator>();
+            check_backend::<SystemAllocator>();
+            check_backend::<SizeClassAllocator>();
+        }
+    }
+
+    #[test]
+    fn test_size_class() {
+        assert_eq!(SizeClassAllocator::class_of(0), Some(0));
+        assert_eq!(SizeClassAllocator::class_of(16), Some(0));
+        assert_eq!(SizeClassAllocator::class_of(17), Some(1));
+        assert_eq!(SizeClassAllocator::class_of(129), Some(8));
+        assert_eq!(SizeClassAllocator::class_of(8192), Some(SIZE_CLASSES.len() - 1));
+        assert_eq!(SizeClassAllocator::class_of(8193), None);
+
+        unsafe {
+            let p = SizeClassAllocator::malloc(700);
+            assert_eq!(SizeClassAllocator::usable_size(p), 768);
+            SizeClassAllocator::free(p);
+            // freed block is the first to be reused
+            let q = SizeClassAllocator::malloc(641);
+            assert_eq!(p, q);
+            SizeClassAllocator::free(q);
+        }
+    }
+}
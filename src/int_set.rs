@@ -1,6 +1,6 @@
 use std::mem::size_of;
 use crate::int_set::Encoding::{INT64, INT16, INT32};
-use crate::{z_malloc_usable, z_realloc_usable, z_free};
+use crate::z_malloc::{z_try_malloc_usable, z_try_realloc_usable, z_free, z_malloc_oom, AllocError};
 use std::fmt::{Display, Formatter, Debug};
 
 #[derive(PartialOrd, PartialEq, Copy, Clone, Debug)]
@@ -54,7 +54,7 @@ impl IntSet {
 
     #[inline]
     fn is_global_empty(&self) -> bool {
-        self.0 == (&EMPTY_SET as *const IntSetInner)
+        std::ptr::eq(self.0, &EMPTY_SET)
     }
 
     #[inline]
@@ -67,9 +67,17 @@ impl IntSet {
         unsafe {&mut *(self.0 as *mut IntSetInner)}
     }
 
+    #[inline]
+    fn resize(&mut self, len: u16) {
+        if let Err(e) = self.try_resize(len) {
+            z_malloc_oom(e.size());
+        }
+    }
+
     // like
     // intset *intsetResize(intset *is, uint32_t len)
-    fn resize(&mut self, len: u16) {
+    // but the set is left untouched if the allocation fails
+    fn try_resize(&mut self, len: u16) -> Result<(), AllocError> {
         let mut inner = self.inner_mut_ref();
         let mut size = len as usize * inner.encoding.byte_size();
         if size > u16::MAX as usize {
@@ -79,17 +87,23 @@ impl IntSet {
             size  += size_of::<IntSetInner>();
             unsafe {
                 let (ptr, usable) = if self.is_global_empty() {
-                    let (ptr, usable) = z_malloc_usable(size as usize);
-                    self.0.copy_to_nonoverlapping(ptr as *mut IntSetInner, 1);
+                    let (ptr, usable) = z_try_malloc_usable(size);
+                    if !ptr.is_null() {
+                        self.0.copy_to_nonoverlapping(ptr as *mut IntSetInner, 1);
+                    }
                     (ptr, usable)
                 } else {
-                    z_realloc_usable(self.0 as *const u8, size)
+                    z_try_realloc_usable(self.0 as *const u8, size)
                 };
+                if ptr.is_null() {
+                    return Err(AllocError::new(size));
+                }
                 self.0 = ptr as *const IntSetInner;
                 inner = self.inner_mut_ref();
-                inner.alloc = (usable - size_of::<IntSetInner>()) as u16;
+                inner.alloc = (usable - size_of::<IntSetInner>()).min(u16::MAX as usize) as u16;
             }
         }
+        Ok(())
     }
 
     // like
@@ -104,7 +118,7 @@ impl IntSet {
                 match inner.encoding {
                     INT16 => self.typed_search::<i16>(value as i16),
                     INT32 => self.typed_search::<i32>(value as i32),
-                    INT64 => self.typed_search::<i64>(value as i64),
+                    INT64 => self.typed_search::<i64>(value),
                 }
             }
         }
@@ -115,15 +129,16 @@ impl IntSet {
         let contents = inner.contents.as_ptr() as *const T;
         let mut min = 0isize;
         let mut max = inner.len as isize - 1;
-        if value > *contents.offset(max) {
+        // contents follow a 6 bytes header, so they may be misaligned for T
+        if value > contents.offset(max).read_unaligned() {
             (false, inner.len as isize)
-        } else if value < *contents {
+        } else if value < contents.read_unaligned() {
             (false, 0)
         } else {
             let mut mid = 0;
             while max >= min {
                 mid = (min + max) >> 1;
-                let mid_value = *contents.offset(mid);
+                let mid_value = contents.offset(mid).read_unaligned();
                 if value > mid_value {
                     min = mid + 1;
                 } else if value < mid_value {
@@ -139,13 +154,21 @@ impl IntSet {
     // like
     // static intset *intsetUpgradeAndAdd(intset *is, int64_t value)
     // value only too max or too min need upgrade, too min mean < 0
-    fn upgrade_and_add(&mut self, value: i64, value_enc: Encoding) -> bool {
+    fn upgrade_and_add(&mut self, value: i64, value_enc: Encoding) -> Result<(), AllocError> {
+        // the global empty set is read only, own a copy before changing the encoding
+        if self.is_global_empty() {
+            self.try_resize(1)?;
+        }
+
         let inner = self.inner_mut_ref();
         let current_enc = inner.encoding;
         let current_len = inner.len;
 
         inner.encoding = value_enc;
-        self.resize(current_len + 1);
+        if let Err(e) = self.try_resize(current_len + 1) {
+            self.inner_mut_ref().encoding = current_enc;
+            return Err(e);
+        }
 
         // resize may realloc so must re ref or rust will complain
         let inner = self.inner_mut_ref();
@@ -181,7 +204,7 @@ impl IntSet {
 
         inner.len += 1;
 
-        return true;
+        Ok(())
     }
 
     unsafe fn move_one_by_one_then_put<F,T>(from: *mut F, size: isize, to: *mut T, value: T, prepend: bool)
@@ -189,15 +212,15 @@ impl IntSet {
         if prepend {
             // prepend, end to start to avoid overlap and every element move one pos
             for i in (0..size).rev() {
-                *to.offset(i + 1) = (*from.offset(i)).into();
+                to.offset(i + 1).write_unaligned(from.offset(i).read_unaligned().into());
             }
-            *to = value;
+            to.write_unaligned(value);
         } else {
             // append, end to start to avoid overlap
             for i in (0..size).rev() {
-                *to.offset(i) = (*from.offset(i)).into();
+                to.offset(i).write_unaligned(from.offset(i).read_unaligned().into());
             }
-            *to.offset(size) = value;
+            to.offset(size).write_unaligned(value);
         }
     }
 
@@ -207,29 +230,36 @@ impl IntSet {
         let from = (inner.contents.as_ptr() as *mut T).offset(pos);
         if need_move {
             let count = inner.len as isize - pos;
-            from.copy_to(from.offset(1), count as usize);
+            // move as bytes, from may be misaligned for T
+            (from as *const u8).copy_to(from.offset(1) as *mut u8, count as usize * size_of::<T>());
         }
-        *from = value;
+        from.write_unaligned(value);
         inner.len += 1;
     }
 
     // like
     // intset *intsetAdd(intset *is, int64_t value, uint8_t *success)
-    fn insert(&mut self, value: i64) -> bool {
+    pub fn insert(&mut self, value: i64) -> bool {
+        self.try_insert(value).unwrap_or_else(|e| z_malloc_oom(e.size()))
+    }
+
+    /// Same as `insert` but returns an error instead of calling the oom
+    /// handler when the allocation fails. The set is untouched on error.
+    pub fn try_insert(&mut self, value: i64) -> Result<bool, AllocError> {
         let value_enc = Encoding::value_encoding(value);
 
         if value_enc > self.inner_ref().encoding {
-            self.upgrade_and_add(value, value_enc);
-            return true;
+            self.upgrade_and_add(value, value_enc)?;
+            return Ok(true);
         }
 
         let (exist, pos) = self.search(value);
         if exist {
-            return false;
+            return Ok(false);
         }
 
         let len = self.inner_ref().len;
-        self.resize(len + 1);
+        self.try_resize(len + 1)?;
         let len = len as isize;
         let need_move = pos < len;
         let inner = self.inner_ref();
@@ -237,14 +267,14 @@ impl IntSet {
             match inner.encoding {
                 INT16 => self.put_one(pos, value as i16, need_move),
                 INT32 => self.put_one(pos, value as i32, need_move),
-                INT64 => self.put_one(pos, value as i64, need_move),
+                INT64 => self.put_one(pos, value, need_move),
             }
         }
 
-        true
+        Ok(true)
     }
 
-    fn remove(&mut self, value: i64) -> bool {
+    pub fn remove(&mut self, value: i64) -> bool {
         let inner = self.inner_ref();
         let enc = inner.encoding;
         let value_enc = Encoding::value_encoding(value);
@@ -260,21 +290,10 @@ impl IntSet {
         let last_idx = (len - 1) as isize;
         if pos < last_idx {
             unsafe {
-                let count = (last_idx - pos) as usize;
-                match enc {
-                    INT16 => {
-                        let from = inner.contents.as_ptr() as *mut i16;
-                        from.offset(1).copy_to(from, count);
-                    }
-                    INT32 => {
-                        let from = inner.contents.as_ptr() as *mut i32;
-                        from.offset(1).copy_to(from, count);
-                    }
-                    INT64 => {
-                        let from = inner.contents.as_ptr() as *mut i64;
-                        from.offset(1).copy_to(from, count);
-                    }
-                }
+                let size = enc.byte_size();
+                let count = (last_idx - pos) as usize * size;
+                let from = (inner.contents.as_ptr() as *mut u8).add(pos as usize * size);
+                from.add(size).copy_to(from, count);
             }
         }
 
@@ -288,6 +307,10 @@ impl IntSet {
         self.inner_ref().len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     // like
     // uint8_t intsetFind(intset *is, int64_t value)
     pub fn contain(&self, value: i64) -> bool {
@@ -313,7 +336,7 @@ impl IntSet {
             unsafe { return Some(self.get_unchecked(index)); }
         }
 
-        return None;
+        None
     }
 
     // like
@@ -329,15 +352,15 @@ impl IntSet {
         match enc {
             INT16 => {
                 let contents = contents as *const i16;
-                *contents.offset(index) as i64
+                contents.offset(index).read_unaligned() as i64
             }
             INT32 => {
                 let contents = contents as *const i32;
-                *contents.offset(index) as i64
+                contents.offset(index).read_unaligned() as i64
             }
             INT64 => {
                 let contents = contents as *const i64;
-                *contents.offset(index)
+                contents.offset(index).read_unaligned()
             }
         }
     }
@@ -346,29 +369,37 @@ impl IntSet {
 
     // like
     // static void _intsetSet(intset *is, int pos, int64_t value)
+    #[allow(dead_code)]
     unsafe fn set_unchecked(&mut self, index: isize, value: i64) {
         let inner = self.inner_mut_ref();
         Self::set_with_encoded(inner.contents.as_mut_ptr(), index, value, inner.encoding);
     }
 
+    #[allow(dead_code)]
     unsafe fn set_with_encoded(contents: *mut u8, index: isize, value: i64, enc: Encoding) {
         match enc {
             INT16 => {
                 let contents = contents as *mut i16;
-                *contents.offset(index) = value as i16;
+                contents.offset(index).write_unaligned(value as i16);
             }
             INT32 => {
                 let contents = contents as *mut i32;
-                *contents.offset(index) = value as i32;
+                contents.offset(index).write_unaligned(value as i32);
             }
             INT64 => {
                 let contents = contents as *mut i64;
-                *contents.offset(index) = value;
+                contents.offset(index).write_unaligned(value);
             }
         }
     }
 }
 
+impl Default for IntSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for IntSet {
     fn drop(&mut self) {
         if !self.is_global_empty() {
@@ -382,13 +413,13 @@ impl Drop for IntSet {
 impl Display for IntSet {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let len = self.len() as isize;
-        let _ = write!(f, "{}", '[');
+        let _ = write!(f, "[");
         unsafe {
             for i in 0..len {
                 let _ = write!(f, "{}, ", self.get_unchecked(i));
             }
         }
-        write!(f, "{}", ']')
+        write!(f, "]")
     }
 }
 
@@ -415,4 +446,40 @@ fn test_basic() {
     assert_eq!(Encoding::INT64, set.inner_ref().encoding);
 
     println!("{}", set);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_try_insert() {
+    let mut set = IntSet::new();
+    assert_eq!(Ok(true), set.try_insert(i64::MIN));
+    assert_eq!(Ok(false), set.try_insert(i64::MIN));
+    assert_eq!(Ok(true), set.try_insert(3));
+    assert_eq!(Some(i64::MIN), set.get(0));
+    assert_eq!(Some(3), set.get(1));
+    assert_eq!(Encoding::INT64, set.inner_ref().encoding);
+}
+
+#[test]
+fn test_upgrade_and_add_fault() {
+    use crate::z_malloc::z_malloc_fail_nth;
+
+    // the global empty set must stay untouched
+    let mut set = IntSet::new();
+    z_malloc_fail_nth(1);
+    assert!(set.try_insert(i64::MAX).is_err());
+    assert!(set.is_global_empty());
+    assert_eq!(Encoding::INT16, set.inner_ref().encoding);
+
+    for i in 0..100 {
+        set.insert(i);
+    }
+    z_malloc_fail_nth(1);
+    assert!(set.try_insert(i64::MIN).is_err());
+    assert_eq!(Encoding::INT16, set.inner_ref().encoding);
+    assert_eq!(100, set.len());
+    assert_eq!(Some(99), set.get(99));
+
+    assert_eq!(Ok(true), set.try_insert(i64::MIN));
+    assert_eq!(Some(i64::MIN), set.get(0));
+    assert_eq!(Some(99), set.get(100));
+}
@@ -1,8 +1,23 @@
+mod allocator;
+#[cfg(any(test, feature = "alloc-track"))]
+mod alloc_track;
 mod z_malloc;
+mod memory_doctor;
 mod sds;
+mod sds_rc;
+mod sds_slice;
 mod ad_list;
 mod int_set;
+mod util;
 
-pub use sds::Sds;
+pub use sds::{Sds, FmtArg, ParseError, TemplateError};
+pub use sds_rc::SdsRc;
+pub use sds_slice::SdsSlice;
+pub use int_set::IntSet;
 pub use ad_list::*;
-pub use z_malloc::*;
\ No newline at end of file
+pub use z_malloc::*;
+pub use util::*;
+pub use memory_doctor::{memory_doctor, memory_doctor_report, MemoryStats};
+#[cfg(any(test, feature = "alloc-track"))]
+pub use alloc_track::*;
+pub use allocator::{ZAllocator, LibcAllocator, SystemAllocator, SizeClassAllocator};
\ No newline at end of file
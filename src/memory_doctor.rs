@@ -0,0 +1,154 @@
+use std::fmt::Write;
+
+use crate::z_malloc::{
+    used_memory, used_memory_peak, z_malloc_get_fragmentation_ratio, z_malloc_get_rss, Z_MALLOC_LIB,
+};
+
+/// A snapshot of the memory counters the doctor looks at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryStats {
+    /// bytes allocated by z_malloc and not freed
+    pub used: usize,
+    /// highest `used` seen so far
+    pub peak: usize,
+    /// resident set size of the process
+    pub rss: usize,
+    /// rss / used
+    pub fragmentation: f32,
+}
+
+impl MemoryStats {
+    /// Reads the counters of the current process.
+    pub fn current() -> Self {
+        let used = used_memory();
+        let rss = z_malloc_get_rss();
+        Self {
+            used,
+            peak: used_memory_peak().max(used),
+            rss,
+            fragmentation: z_malloc_get_fragmentation_ratio(rss),
+        }
+    }
+}
+
+// below this the ratios say nothing, same threshold as redis
+const DOCTOR_MIN_USED: usize = 5 * 1024 * 1024;
+// fragmentation only matters when it also wastes real memory
+const DOCTOR_MIN_FRAG_BYTES: usize = 10 * 1024 * 1024;
+
+// same as
+// void bytesToHuman(char *s, unsigned long long n)
+fn bytes_to_human(n: usize) -> String {
+    let n = n as f64;
+    if n < 1024.0 {
+        format!("{}B", n)
+    } else if n < 1024.0 * 1024.0 {
+        format!("{:.2}K", n / 1024.0)
+    } else if n < 1024.0 * 1024.0 * 1024.0 {
+        format!("{:.2}M", n / (1024.0 * 1024.0))
+    } else {
+        format!("{:.2}G", n / (1024.0 * 1024.0 * 1024.0))
+    }
+}
+
+/// same as
+///
+/// sds getMemoryDoctorReport(void)
+///
+/// Human readable advice about the memory of the process, what MEMORY DOCTOR
+/// prints.
+pub fn memory_doctor() -> String {
+    memory_doctor_report(&MemoryStats::current())
+}
+
+/// The `memory_doctor` report for the given counters.
+pub fn memory_doctor_report(stats: &MemoryStats) -> String {
+    let mut report = String::new();
+    let _ = writeln!(
+        report,
+        "used: {}, peak: {}, rss: {}, fragmentation: {:.2}, allocator: {}",
+        bytes_to_human(stats.used),
+        bytes_to_human(stats.peak),
+        bytes_to_human(stats.rss),
+        stats.fragmentation,
+        Z_MALLOC_LIB,
+    );
+    report.push('\n');
+
+    if stats.used < DOCTOR_MIN_USED {
+        report.push_str(
+            "This process is empty or is using very little memory, \
+             the issues detector can't be used in these conditions.\n",
+        );
+        return report;
+    }
+
+    let big_peak = stats.peak as f64 / stats.used as f64 > 1.5;
+    let high_frag = stats.fragmentation > 1.4
+        && stats.rss.saturating_sub(stats.used) > DOCTOR_MIN_FRAG_BYTES;
+
+    if !big_peak && !high_frag {
+        report.push_str("No memory issue found.\n");
+        return report;
+    }
+
+    report.push_str("The following issues were found:\n\n");
+    if big_peak {
+        let _ = writeln!(
+            report,
+            " * Peak memory: in the past this process used more than 150% of the \
+             memory it is using now ({} against {}). The allocator normally keeps \
+             the pages after a peak, so a big fragmentation ratio is expected and \
+             harmless: the memory is reused as soon as more data comes in. To \
+             reclaim it now call z_malloc_purge(), the only other option is to \
+             restart the process.\n",
+            bytes_to_human(stats.peak),
+            bytes_to_human(stats.used),
+        );
+    }
+    if high_frag {
+        let _ = writeln!(
+            report,
+            " * High fragmentation: the RSS is {:.2} times the memory allocated \
+             ({} wasted). This is usually due either to a large peak memory (see \
+             above) or to a workload that fragments the allocator a lot. If there \
+             was no peak, try z_malloc_purge() or a size class allocator; the \
+             allocator in use is \"{}\".\n",
+            stats.fragmentation,
+            bytes_to_human(stats.rss - stats.used),
+            Z_MALLOC_LIB,
+        );
+    }
+    report
+}
+
+#[test]
+fn test_memory_doctor_report() {
+    const MB: usize = 1024 * 1024;
+    let stats = |used: usize, peak: usize, rss: usize| MemoryStats {
+        used,
+        peak,
+        rss,
+        fragmentation: rss as f32 / used as f32,
+    };
+
+    let report = memory_doctor_report(&stats(MB, MB, 8 * MB));
+    assert!(report.contains("used: 1.00M"));
+    assert!(report.contains("very little memory"));
+
+    let report = memory_doctor_report(&stats(100 * MB, 110 * MB, 120 * MB));
+    assert!(report.contains("No memory issue"));
+
+    let report = memory_doctor_report(&stats(100 * MB, 300 * MB, 120 * MB));
+    assert!(report.contains("Peak memory"));
+    assert!(!report.contains("High fragmentation"));
+
+    let report = memory_doctor_report(&stats(100 * MB, 300 * MB, 300 * MB));
+    assert!(report.contains("Peak memory"));
+    assert!(report.contains("High fragmentation"));
+    assert!(report.contains("200.00M wasted"));
+
+    assert_eq!(bytes_to_human(100), "100B");
+    assert_eq!(bytes_to_human(1536), "1.50K");
+    assert_eq!(bytes_to_human(3 << 30), "3.00G");
+}
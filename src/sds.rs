@@ -1,24 +1,44 @@
 use std::ops::{Sub, Deref, DerefMut};
 use std::fmt::{Display, Formatter, Debug};
 use std::fmt;
+use std::borrow::Cow;
+use std::str::Utf8Error;
 use std::cmp::Ordering;
-
+use std::error::Error;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicUsize;
+use std::hash::{Hash, Hasher};
+use std::borrow::Borrow;
+use std::iter::FromIterator;
+use std::io;
+use std::ops::RangeBounds;
+
+use crate::sds_slice::SdsSlice;
+use crate::util::{ll2str, string2ld, string2ll, string2ull, ull2str, LONG_STR_SIZE};
 use crate::z_malloc::{
+    AllocError,
+    z_malloc_oom as s_malloc_oom,
     z_free as s_free,
-    z_malloc_usable as s_malloc_usable,
-    z_realloc_usable as s_realloc_usable,
     z_try_malloc_usable as s_try_malloc_usable,
+    z_try_realloc_usable as s_try_realloc_usable,
 };
 
+const SDS_TYPE_5: u8 = 0;
 const SDS_TYPE_8: u8 = 1;
 const SDS_TYPE_16: u8 = 2;
 const SDS_TYPE_32: u8 = 3;
 const SDS_TYPE_64: u8 = 4;
 const SDS_TYPE_MASK: u8 = 7;
+const SDS_TYPE_BITS: u8 = 3;
+// set in the flags of a block starting with a reference count, the header
+// coming after it, see SdsRc. Type 5 keeps its len in these bits and is
+// never shared.
+const SDS_RC: u8 = 1 << SDS_TYPE_BITS;
+const SDS_RC_SIZE: usize = std::mem::size_of::<AtomicUsize>();
 
 const SDS_MAX_PRE_ALLOC: usize = 1024 * 1024;
 
-#[repr(packed)]
+#[repr(C, packed)]
 struct SdsHdr<T> {
     len: T,
     alloc: T,
@@ -31,11 +51,224 @@ type SdsHdr16 = SdsHdr<u16>;
 type SdsHdr32 = SdsHdr<u32>;
 type SdsHdr64 = SdsHdr<u64>;
 
+// type 5 has no struct, its header is the flags byte alone, holding the
+// type in the low 3 bits and the len in the high 5 bits. There is no alloc,
+// the len is the alloc, so it is only used for strings not expected to grow
+// and is promoted to type 8 on the first append.
+
+// same as
+// #define SDS_TYPE_5_LEN(f) ((f)>>SDS_TYPE_BITS)
+#[inline]
+fn sds_type_5_len(flags: u8) -> usize {
+    (flags >> SDS_TYPE_BITS) as usize
+}
+
 pub struct Sds(*const u8);
 
+// A plain sds owns its buffer alone. A block with SDS_RC is aliased by
+// several Sds, the ptr::read copies inside SdsRc, but it is only reached
+// through SdsRc, which keeps the count atomic and only hands out &mut Sds
+// once the count is 1, so an aliased block is never written. The shared
+// empty sds is never written to either. An sds can then move to and be read
+// from any thread, the allocator behind z_malloc being thread safe.
+unsafe impl Send for Sds {}
+unsafe impl Sync for Sds {}
+
+/// An argument of `Sds::cat_fmt`, each kind goes with one directive.
+#[derive(Debug, Clone, Copy)]
+pub enum FmtArg<'a> {
+    /// %s
+    Str(&'a [u8]),
+    /// %S
+    Sds(&'a Sds),
+    /// %i
+    Int(i32),
+    /// %I
+    Long(i64),
+    /// %u
+    UInt(u32),
+    /// %U
+    ULong(u64),
+}
+
+impl<'a> From<&'a str> for FmtArg<'a> {
+    fn from(s: &'a str) -> Self {
+        FmtArg::Str(s.as_bytes())
+    }
+}
+
+impl<'a> From<&'a [u8]> for FmtArg<'a> {
+    fn from(s: &'a [u8]) -> Self {
+        FmtArg::Str(s)
+    }
+}
+
+impl<'a> From<&'a Sds> for FmtArg<'a> {
+    fn from(s: &'a Sds) -> Self {
+        FmtArg::Sds(s)
+    }
+}
+
+impl From<i32> for FmtArg<'_> {
+    fn from(v: i32) -> Self {
+        FmtArg::Int(v)
+    }
+}
+
+impl From<i64> for FmtArg<'_> {
+    fn from(v: i64) -> Self {
+        FmtArg::Long(v)
+    }
+}
+
+impl From<isize> for FmtArg<'_> {
+    fn from(v: isize) -> Self {
+        FmtArg::Long(v as i64)
+    }
+}
+
+impl From<u32> for FmtArg<'_> {
+    fn from(v: u32) -> Self {
+        FmtArg::UInt(v)
+    }
+}
+
+impl From<u64> for FmtArg<'_> {
+    fn from(v: u64) -> Self {
+        FmtArg::ULong(v)
+    }
+}
+
+impl From<usize> for FmtArg<'_> {
+    fn from(v: usize) -> Self {
+        FmtArg::ULong(v as u64)
+    }
+}
+
+/// The error of `Sds::split_args`, the line is not a valid argument list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// a quoted argument is never closed
+    UnbalancedQuotes,
+    /// a closing quote is followed by something other than a space
+    TrailingAfterQuote,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnbalancedQuotes => f.write_str("unbalanced quotes"),
+            ParseError::TrailingAfterQuote => f.write_str("closing quote must be followed by a space"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// The error of `Sds::template`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// the callback has no value for this variable
+    UnknownVariable(Sds),
+    /// a '{' is never closed, or a '}' was never opened
+    UnbalancedBraces,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownVariable(name) => write!(f, "unknown template variable {:?}", name),
+            TemplateError::UnbalancedBraces => f.write_str("unbalanced braces in template"),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+// same as
+// int is_hex_digit(char c)
+#[inline]
+fn is_hex_digit(c: u8) -> bool {
+    c.is_ascii_hexdigit()
+}
+
+// same as
+// int hex_digit_to_int(char c)
+#[inline]
+fn hex_digit_to_int(c: u8) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        b'A'..=b'F' => c - b'A' + 10,
+        _ => 0,
+    }
+}
+
+// same as
+// int isprint(int c) in the C locale
+#[inline]
+fn is_print(c: u8) -> bool {
+    (0x20..0x7f).contains(&c)
+}
+
+// c as written by sdscatrepr, always ascii
+fn repr_escape(c: u8, buf: &mut [u8; 4]) -> &[u8] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let len = match c {
+        b'\\' | b'"' => {
+            buf[..2].copy_from_slice(&[b'\\', c]);
+            2
+        }
+        b'\n' | b'\r' | b'\t' | 0x07 | 0x08 => {
+            let escaped = match c {
+                b'\n' => b'n',
+                b'\r' => b'r',
+                b'\t' => b't',
+                0x07 => b'a',
+                _ => b'b',
+            };
+            buf[..2].copy_from_slice(&[b'\\', escaped]);
+            2
+        }
+        c if is_print(c) => {
+            buf[0] = c;
+            1
+        }
+        c => {
+            buf.copy_from_slice(&[b'\\', b'x', HEX[(c >> 4) as usize], HEX[(c & 0xf) as usize]]);
+            4
+        }
+    };
+    &buf[..len]
+}
+
+// same as
+// int isspace(int c) in the C locale
+#[inline]
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r')
+}
+
+// same as
+// int sdsll2str(char *s, long long value)
+// on top of the faster util::ll2str
+fn sds_ll2str(buf: &mut [u8; LONG_STR_SIZE], value: i64) -> &[u8] {
+    let len = ll2str(buf, value).unwrap();
+    &buf[..len]
+}
+
+// same as
+// int sdsull2str(char *s, unsigned long long v)
+fn sds_ull2str(buf: &mut [u8; LONG_STR_SIZE], value: u64) -> &[u8] {
+    let len = ull2str(buf, value).unwrap();
+    &buf[..len]
+}
+
 #[inline]
 fn sds_hdr_size(sds_type: u8) -> usize {
     match sds_type & SDS_TYPE_MASK {
+        SDS_TYPE_5 => 1,
         SDS_TYPE_8 => std::mem::size_of::<SdsHdr8>(),
         SDS_TYPE_16 => std::mem::size_of::<SdsHdr16>(),
         SDS_TYPE_32 => std::mem::size_of::<SdsHdr32>(),
@@ -46,7 +279,9 @@ fn sds_hdr_size(sds_type: u8) -> usize {
 
 #[inline]
 fn sds_req_type(string_size: usize) -> u8 {
-    if string_size < 1 << 8 {
+    if string_size < 1 << 5 {
+        SDS_TYPE_5
+    } else if string_size < 1 << 8 {
         SDS_TYPE_8
     } else if string_size < 1 << 16 {
         SDS_TYPE_16
@@ -60,10 +295,11 @@ fn sds_req_type(string_size: usize) -> u8 {
 #[inline]
 fn sds_type_max_size(sds_type: u8) -> usize {
     match sds_type {
-        SDS_TYPE_8 => u8::max_value() as usize,
-        SDS_TYPE_16 => u16::max_value() as usize,
-        SDS_TYPE_32 => u32::max_value() as usize,
-        SDS_TYPE_64 => u64::max_value() as usize,
+        SDS_TYPE_5 => (1 << 5) - 1,
+        SDS_TYPE_8 => u8::MAX as usize,
+        SDS_TYPE_16 => u16::MAX as usize,
+        SDS_TYPE_32 => u32::MAX as usize,
+        SDS_TYPE_64 => u64::MAX as usize,
         _ => unimplemented!("sds_type unknown: {}", sds_type),
     }
 }
@@ -72,16 +308,17 @@ impl<T: Sub<Output=T> + Into<u64> + Copy> SdsHdr<T> {
     // same as
     // #define SDS_HDR(T,s) ((struct sdshdr##T *)((s)-(sizeof(struct sdshdr##T))))
     #[inline]
+    #[allow(clippy::self_named_constructors)]
     fn sds_hdr(sds: &Sds) -> &Self {
         unsafe {
-            &*(sds.0.offset(-(std::mem::size_of::<Self>() as isize)) as *const Self)
+            &*(sds.0.sub(std::mem::size_of::<Self>()) as *const Self)
         }
     }
 
     #[inline]
-    fn mut_sds_hdr(sds: &Sds) -> &mut Self {
+    fn mut_sds_hdr(sds: &mut Sds) -> &mut Self {
         unsafe {
-            &mut *(sds.0.offset(-(std::mem::size_of::<Self>() as isize)) as *mut Self)
+            &mut *(sds.0.sub(std::mem::size_of::<Self>()) as *mut Self)
         }
     }
 
@@ -116,20 +353,24 @@ impl Sds {
     // same as
     // sds sdsnewlen(const void *init, size_t initlen)
     pub fn from_slice(init: &[u8]) -> Self {
-        Self::from_raw_pointer(init.as_ptr(), init.len(), false)
+        Self::from_raw_pointer(init.as_ptr(), init.len())
     }
 
-    // same as
-    // sds sdstrynewlen(const void *init, size_t initlen)
-    #[allow(dead_code)]
-    fn try_from_slice(init: &[u8]) -> Self {
-        Self::from_raw_pointer(init.as_ptr(), init.len(), true)
+    /// same as
+    ///
+    /// sds sdstrynewlen(const void *init, size_t initlen)
+    ///
+    /// but returns an error instead of calling the oom handler when the
+    /// allocation fails.
+    pub fn try_from_slice(init: &[u8]) -> Result<Self, AllocError> {
+        Self::try_from_raw_pointer(init.as_ptr(), init.len())
     }
 
     // like
     // sds sdsnew(const char *init)
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(init: &str) -> Self {
-        Self::from_raw_pointer(init.as_ptr(), init.len(), false)
+        Self::from_raw_pointer(init.as_ptr(), init.len())
     }
 
     pub fn as_slice(&self) -> &[u8] {
@@ -139,22 +380,67 @@ impl Sds {
         }
     }
 
-    pub fn as_mut_slice(&self) -> &mut [u8] {
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
         unsafe {
             let slice_ptr = std::ptr::slice_from_raw_parts(self.0, self.len());
             &mut *(slice_ptr as *mut [u8])
         }
     }
 
-    // may be illegal utf8 string
+    /// The content as a str, or the error if it is not valid utf8, which an
+    /// sds holding binary data is not.
+    pub fn as_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(self.as_slice())
+    }
+
+    /// The content as a str, with every invalid utf8 sequence replaced by
+    /// U+FFFD. Only allocates if there is something to replace.
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.as_slice())
+    }
+
+    /// A view of the bytes in `range`, borrowed from the sds, no allocation.
+    ///
+    /// # Panics
+    ///
+    /// If `range` is out of bounds, like slice indexing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::Sds;
+    ///
+    /// let query = Sds::from_str("SET counter 42");
+    /// let value = query.slice(12..);
+    /// assert_eq!(value, "42");
+    /// assert_eq!(value.to_i64(), Some(42));
+    /// ```
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> SdsSlice<'_> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        SdsSlice::new(&self.as_slice()[bounds])
+    }
+
+    /// The content as an i64, with the strict rules of `string2ll`.
+    pub fn to_i64(&self) -> Option<i64> {
+        string2ll(self.as_slice())
+    }
+
+    /// The content as a u64, with the strict rules of `string2ull`.
+    pub fn to_u64(&self) -> Option<u64> {
+        string2ull(self.as_slice())
+    }
+
+    /// The content as an f64, see `string2ld`.
+    pub fn to_f64(&self) -> Option<f64> {
+        string2ld(self.as_slice())
+    }
+
+    /// # Panics
+    ///
+    /// If the content is not valid utf8.
+    #[deprecated(note = "use as_str or to_str_lossy, an sds can hold any bytes")]
     pub fn as_str_uncheck(&self) -> &str {
-        unsafe {
-            let len = self.len();
-            let s = String::from_raw_parts(self.0 as *mut u8, len, len);
-            let fake = &*(&s as *const String);
-            std::mem::forget(s);
-            fake
-        }
+        self.as_str().expect("sds is not valid utf8")
     }
 
     /// same as
@@ -176,7 +462,7 @@ impl Sds {
     pub fn empty() -> Self {
         Sds(unsafe {
             (&EMPTY_HDR as *const SdsHdr8 as *const u8)
-                .offset(std::mem::size_of_val(&EMPTY_HDR) as isize)
+                .add(std::mem::size_of_val(&EMPTY_HDR))
         })
     }
 
@@ -186,64 +472,94 @@ impl Sds {
     }
 
     #[inline]
-    fn is_global_empty(&self) -> bool {
+    pub(crate) fn is_global_empty(&self) -> bool {
         unsafe {
-            self.0.offset(-(sds_hdr_size(self.type_code()) as isize))
-                == (&EMPTY_HDR as *const SdsHdr8 as *const u8)
+            std::ptr::eq(self.0.sub(sds_hdr_size(self.type_code())),
+                         &EMPTY_HDR as *const SdsHdr8 as *const u8)
         }
     }
 
+    // an empty sds with room for exactly capacity bytes
+    // never type 5, which has no room
+    fn with_capacity(capacity: usize) -> Self {
+        let mut sds = Self::empty();
+        sds.reserve_exact(capacity);
+        sds
+    }
+
+    #[inline]
+    fn from_raw_pointer(init: *const u8, init_len: usize) -> Self {
+        Self::try_from_raw_pointer(init, init_len).unwrap_or_else(|e| s_malloc_oom(e.size()))
+    }
+
     // same as
     // sds _sdsnewlen(const void *init, size_t initlen, int trymalloc)
-    // but no \0 at end any more
-    fn from_raw_pointer(init: *const u8, init_len: usize, try_malloc: bool) -> Self {
+    // with trymalloc, but no \0 at end any more, and the uninitialized
+    // content left as is when init is null
+    fn try_from_raw_pointer(init: *const u8, init_len: usize) -> Result<Self, AllocError> {
         if init_len == 0 {
-            return Self::empty();
+            return Ok(Self::empty());
         }
-        let sds_type = sds_req_type(init_len);
-        let hdr_size = sds_hdr_size(sds_type);
-        let total_size = init_len + hdr_size;
-        let (sh, mut usable) = if try_malloc {
-            s_try_malloc_usable(total_size)
+        Self::try_new(init, init_len, false)
+    }
+
+    // same as try_from_slice, but the block starts with a reference count
+    // of 1, for SdsRc. Never the shared empty sds, nor type 5.
+    pub(crate) fn try_from_slice_shared(init: &[u8]) -> Result<Self, AllocError> {
+        Self::try_new(init.as_ptr(), init.len(), true)
+    }
+
+    fn try_new(init: *const u8, init_len: usize, rc: bool) -> Result<Self, AllocError> {
+        let (sds_type, prefix) = if rc {
+            (sds_req_type(init_len).max(SDS_TYPE_8), SDS_RC_SIZE)
         } else {
-            s_malloc_usable(total_size)
+            (sds_req_type(init_len), 0)
         };
-
+        let hdr_size = sds_hdr_size(sds_type);
+        let total_size = prefix + hdr_size + init_len;
+        let (sh, mut usable) = s_try_malloc_usable(total_size);
         if sh.is_null() {
-            panic!("malloc error");
+            return Err(AllocError::new(total_size));
+        }
+        if rc {
+            unsafe { (sh as *mut AtomicUsize).write(AtomicUsize::new(1)); }
         }
 
-        usable -= hdr_size;
+        usable -= prefix + hdr_size;
         usable = usable.min(sds_type_max_size(sds_type));
 
-        let sds = Sds(unsafe { sh.offset(hdr_size as isize) });
+        let mut sds = Sds(unsafe { sh.add(prefix + hdr_size) });
         match sds_type {
+            SDS_TYPE_5 => unsafe { sds.set_type_5_len(init_len) },
             SDS_TYPE_8 => {
-                let hdr = SdsHdr8::mut_sds_hdr(&sds);
+                let hdr = SdsHdr8::mut_sds_hdr(&mut sds);
                 hdr.len = init_len as u8;
                 hdr.alloc = usable as u8;
                 hdr._flags = SDS_TYPE_8;
             }
             SDS_TYPE_16 => {
-                let hdr = SdsHdr16::mut_sds_hdr(&sds);
+                let hdr = SdsHdr16::mut_sds_hdr(&mut sds);
                 hdr.len = init_len as u16;
                 hdr.alloc = usable as u16;
                 hdr._flags = SDS_TYPE_16;
             }
             SDS_TYPE_32 => {
-                let hdr = SdsHdr32::mut_sds_hdr(&sds);
+                let hdr = SdsHdr32::mut_sds_hdr(&mut sds);
                 hdr.len = init_len as u32;
                 hdr.alloc = usable as u32;
                 hdr._flags = SDS_TYPE_32;
             }
             SDS_TYPE_64 => {
-                let hdr = SdsHdr64::mut_sds_hdr(&sds);
+                let hdr = SdsHdr64::mut_sds_hdr(&mut sds);
                 hdr.len = init_len as u64;
                 hdr.alloc = usable as u64;
                 hdr._flags = SDS_TYPE_64;
             }
             _ => unreachable!(),
         }
+        if rc {
+            unsafe { *(sds.0.sub(1) as *mut u8) |= SDS_RC; }
+        }
 
         if !init.is_null() {
             unsafe {
@@ -251,77 +567,258 @@ impl Sds {
             }
         }
 
-        sds
+        Ok(sds)
+    }
+
+    /// same as
+    ///
+    /// sds sdsfromlonglong(long long value)
+    pub fn from_i64(value: i64) -> Self {
+        let mut buf = [0u8; LONG_STR_SIZE];
+        Self::from_slice(sds_ll2str(&mut buf, value))
+    }
+
+    /// `from_i64` for u64.
+    pub fn from_u64(value: u64) -> Self {
+        let mut buf = [0u8; LONG_STR_SIZE];
+        Self::from_slice(sds_ull2str(&mut buf, value))
     }
 
     // same as
     // void sdsclear(sds s)
     pub fn clear(&mut self) {
-        unsafe { self.set_len_uncheck(0); }
+        // the shared empty header is read only
+        if !self.is_global_empty() {
+            unsafe { self.set_len_uncheck(0); }
+        }
     }
 
     // same as
-    // sds sdsMakeRoomFor(sds s, size_t addlen)
-    fn make_room_for(&mut self, inc_len: usize) {
+    // sds _sdsMakeRoomFor(sds s, size_t addlen, int greedy)
+    // but the sds is left untouched if the allocation fails
+    fn try_make_room(&mut self, inc_len: usize, greedy: bool) -> Result<(), AllocError> {
         let avail = self.avail();
         if avail >= inc_len {
-            return;
+            return Ok(());
         }
 
         let len = self.len();
-        let mut new_len = len + inc_len;
-        if new_len < SDS_MAX_PRE_ALLOC {
-            new_len *= 2;
-        } else {
-            new_len += SDS_MAX_PRE_ALLOC;
+        let mut new_len = len.checked_add(inc_len).ok_or_else(|| AllocError::new(usize::MAX))?;
+        if greedy {
+            if new_len < SDS_MAX_PRE_ALLOC {
+                new_len *= 2;
+            } else {
+                new_len = new_len.saturating_add(SDS_MAX_PRE_ALLOC);
+            }
         }
 
         let old_type = self.type_code();
-        let new_type = sds_req_type(new_len);
+        let prefix = self.prefix_size();
+        // type 5 cannot remember the free space, and the string is growing
+        let new_type = sds_req_type(new_len).max(SDS_TYPE_8);
         let hdr_len = sds_hdr_size(new_type);
+        let size = (prefix + hdr_len).checked_add(new_len).ok_or_else(|| AllocError::new(usize::MAX))?;
         let mut usable = unsafe {
-            let sh = self.0.offset(-(sds_hdr_size(old_type) as isize));
+            let sh = self.block();
             if old_type == new_type && !self.is_global_empty() {
-                let (new_sh, usable) = s_realloc_usable(sh, hdr_len + new_len);
+                let (new_sh, usable) = s_try_realloc_usable(sh, size);
                 if new_sh.is_null() {
-                    panic!("s_realloc_usable {} size error", hdr_len + new_len);
+                    return Err(AllocError::new(size));
                 }
-                self.0 = new_sh.offset(hdr_len as isize);
+                self.0 = new_sh.add(prefix + hdr_len);
                 usable
             } else {
-                let (new_sh, usable) = s_malloc_usable(hdr_len + new_len);
+                let (new_sh, usable) = s_try_malloc_usable(size);
                 if new_sh.is_null() {
-                    panic!("s_malloc_usable {} size error", hdr_len + new_len);
+                    return Err(AllocError::new(size));
                 }
-                let new_s = new_sh.offset(hdr_len as isize) as *mut u8;
+                // the reference count, if any, goes along
+                sh.copy_to_nonoverlapping(new_sh as *mut u8, prefix);
+                let new_s = new_sh.add(prefix + hdr_len) as *mut u8;
                 self.0.copy_to_nonoverlapping(new_s, len);
+                let rc_flag = if prefix != 0 { SDS_RC } else { 0 };
                 if !self.is_global_empty() {
                     s_free(sh);
                 }
 
                 self.0 = new_s;
-                *(new_s.offset(-1) as *mut u8) = new_type;
+                *new_s.sub(1) = new_type | rc_flag;
                 self.set_len_uncheck(len);
                 usable
             }
         };
 
-        usable -= hdr_len;
+        usable -= prefix + hdr_len;
         usable = usable.min(sds_type_max_size(new_type));
 
         unsafe { self.set_alloc_uncheck(usable); }
+        Ok(())
     }
 
     // same as
-    // sds sdscatlen(sds s, const void *t, size_t len)
+    // sds sdsMakeRoomFor(sds s, size_t addlen)
+    #[inline]
+    fn try_make_room_for(&mut self, inc_len: usize) -> Result<(), AllocError> {
+        self.try_make_room(inc_len, true)
+    }
+
+    #[inline]
+    fn make_room_for(&mut self, inc_len: usize) {
+        if let Err(e) = self.try_make_room_for(inc_len) {
+            s_malloc_oom(e.size());
+        }
+    }
+
+    /// same as
+    ///
+    /// sds sdsMakeRoomFor(sds s, size_t addlen)
+    ///
+    /// Makes room for at least `additional` more bytes. Like the push
+    /// functions it grows greedily, doubling the buffer up to 1MB and adding
+    /// 1MB past that, so that repeated appends are amortized.
+    pub fn reserve(&mut self, additional: usize) {
+        self.make_room_for(additional);
+    }
+
+    /// Same as `reserve` but returns an error instead of calling the oom
+    /// handler when the allocation fails. The sds is untouched on error.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        self.try_make_room_for(additional)
+    }
+
+    /// same as
+    ///
+    /// sds sdsMakeRoomForNonGreedy(sds s, size_t addlen)
+    ///
+    /// Makes room for exactly `additional` more bytes, or a bit more if the
+    /// allocator hands out a bigger block. For a final size known in advance.
+    pub fn reserve_exact(&mut self, additional: usize) {
+        if let Err(e) = self.try_reserve_exact(additional) {
+            s_malloc_oom(e.size());
+        }
+    }
+
+    /// Same as `reserve_exact` but returns an error instead of calling the
+    /// oom handler when the allocation fails. The sds is untouched on error.
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), AllocError> {
+        self.try_make_room(additional, false)
+    }
+
+    /// same as
+    ///
+    /// sds sdsRemoveFreeSpace(sds s, int would_regrow)
+    ///
+    /// Shrinks the buffer to the content, switching to a smaller header when
+    /// the length allows it. See `resize` for `would_regrow`.
+    pub fn remove_free_space(&mut self, would_regrow: bool) {
+        self.resize(self.len(), would_regrow);
+    }
+
+    /// same as
+    ///
+    /// sds sdsResize(sds s, size_t size, int would_regrow)
+    ///
+    /// Reallocates the buffer for exactly `size` bytes, truncating the
+    /// content if it is longer. The header is downgraded to the smallest
+    /// type able to hold `size` when that saves a lot, that is down to
+    /// type 8 or 5, otherwise the old header is kept and the block
+    /// reallocated in place. `would_regrow` tells the string is expected to
+    /// grow back, so the 1 byte header of type 5, which is promoted on the
+    /// next append, is not picked.
+    pub fn resize(&mut self, size: usize, would_regrow: bool) {
+        if let Err(e) = self.try_resize(size, would_regrow) {
+            s_malloc_oom(e.size());
+        }
+    }
+
+    /// Same as `resize` but returns an error instead of calling the oom
+    /// handler when the allocation fails. The sds is untouched on error.
+    pub fn try_resize(&mut self, size: usize, would_regrow: bool) -> Result<(), AllocError> {
+        if self.alloc() == size {
+            return Ok(());
+        }
+
+        let len = self.len().min(size);
+        let old_type = self.type_code();
+        let old_hdr_len = sds_hdr_size(old_type);
+        let prefix = self.prefix_size();
+        let rc_flag = if prefix != 0 { SDS_RC } else { 0 };
+        let mut new_type = sds_req_type(size);
+        // a shared block cannot be type 5, its flags bits are taken, and
+        // type 5 has no alloc field so it cannot keep room past len
+        if (would_regrow || prefix != 0 || size != len) && new_type == SDS_TYPE_5 {
+            new_type = SDS_TYPE_8;
+        }
+        let hdr_len = sds_hdr_size(new_type);
+
+        // realloc if the header stays, or if a smaller one than the current
+        // but still bigger than type 8 would save little
+        let use_realloc = !self.is_global_empty()
+            && (old_type == new_type || (new_type < old_type && new_type > SDS_TYPE_8));
+        unsafe {
+            let sh = self.block();
+            if use_realloc {
+                let new_size = (prefix + old_hdr_len).checked_add(size).ok_or_else(|| AllocError::new(usize::MAX))?;
+                let (new_sh, _) = s_try_realloc_usable(sh, new_size);
+                if new_sh.is_null() {
+                    return Err(AllocError::new(new_size));
+                }
+                self.0 = new_sh.add(prefix + old_hdr_len);
+            } else {
+                let new_size = (prefix + hdr_len).checked_add(size).ok_or_else(|| AllocError::new(usize::MAX))?;
+                let (new_sh, _) = s_try_malloc_usable(new_size);
+                if new_sh.is_null() {
+                    return Err(AllocError::new(new_size));
+                }
+                sh.copy_to_nonoverlapping(new_sh as *mut u8, prefix);
+                let new_s = new_sh.add(prefix + hdr_len) as *mut u8;
+                self.0.copy_to_nonoverlapping(new_s, len);
+                if !self.is_global_empty() {
+                    s_free(sh);
+                }
+
+                self.0 = new_s;
+                *new_s.sub(1) = new_type | rc_flag;
+            }
+            self.set_len_uncheck(len);
+            self.set_alloc_uncheck(size);
+        }
+        Ok(())
+    }
+
+    /// same as
+    ///
+    /// size_t sdsAllocSize(sds s)
+    ///
+    /// Bytes taken by the sds, header included, 0 for the shared empty sds.
+    /// This is the size the sds asked for, whatever the header type, not
+    /// counting the slack the allocator may add on top.
+    pub fn alloc_size(&self) -> usize {
+        if self.is_global_empty() {
+            return 0;
+        }
+        // alloc is len for type 5, which has no room
+        self.prefix_size() + sds_hdr_size(self.type_code()) + self.alloc()
+    }
+
+    #[inline]
     unsafe fn push_from_raw_pointer(&mut self, ptr: *const u8, len: usize) {
+        if let Err(e) = self.try_push_from_raw_pointer(ptr, len) {
+            s_malloc_oom(e.size());
+        }
+    }
+
+    // same as
+    // sds sdscatlen(sds s, const void *t, size_t len)
+    unsafe fn try_push_from_raw_pointer(&mut self, ptr: *const u8, len: usize) -> Result<(), AllocError> {
         if len == 0 {
-            return;
+            return Ok(());
         }
         let old_len = self.len();
-        self.make_room_for(len);
-        ptr.copy_to(self.0.offset(old_len as isize) as *mut u8, len);
+        self.try_make_room_for(len)?;
+        ptr.copy_to(self.0.add(old_len) as *mut u8, len);
         self.set_len_uncheck(old_len + len);
+        Ok(())
     }
 
     pub fn push_str(&mut self, s: &str) -> &mut Self {
@@ -338,6 +835,15 @@ impl Sds {
         }
     }
 
+    /// Same as `push_slice` but returns an error instead of calling the oom
+    /// handler when the allocation fails. The sds is untouched on error.
+    pub fn try_push_slice(&mut self, s: &[u8]) -> Result<&mut Self, AllocError> {
+        unsafe {
+            self.try_push_from_raw_pointer(s.as_ptr(), s.len())?;
+        }
+        Ok(self)
+    }
+
     pub fn push_u8(&mut self, c: u8) -> &mut Self {
         unsafe { self.push_from_raw_pointer(&c, 1); }
         self
@@ -349,35 +855,601 @@ impl Sds {
         }
     }
 
-    #[inline]
-    fn type_code(&self) -> u8 {
-        unsafe {
-            *self.0.offset(-1) as u8
+    /// same as
+    ///
+    /// sds sdscatfmt(sds s, char const *fmt, ...)
+    ///
+    /// Appends `fmt` with every directive replaced by the next of `args`, much
+    /// faster than going through `format!`:
+    ///
+    /// * %s - a string, `FmtArg::Str`
+    /// * %S - an sds, `FmtArg::Sds`
+    /// * %i - an i32, `FmtArg::Int`
+    /// * %I - an i64, `FmtArg::Long`
+    /// * %u - an u32, `FmtArg::UInt`
+    /// * %U - an u64, `FmtArg::ULong`
+    /// * %% - a single '%'
+    ///
+    /// Any other character after '%' is copied alone, as sdscatfmt does.
+    ///
+    /// # Panics
+    ///
+    /// If `args` runs out or an argument does not match its directive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::Sds;
+    ///
+    /// let mut s = Sds::from_str("reply: ");
+    /// let key = Sds::from_str("key");
+    /// s.cat_fmt("%S=%i (%U%%)", &[(&key).into(), (-3).into(), 100u64.into()]);
+    /// assert_eq!(s.as_slice(), b"reply: key=-3 (100%)");
+    /// ```
+    pub fn cat_fmt(&mut self, fmt: &str, args: &[FmtArg]) -> &mut Self {
+        let fmt = fmt.as_bytes();
+        // most of the times the result is not longer than twice the format
+        self.make_room_for(fmt.len() * 2);
+
+        let mut args = args.iter();
+        // start of the literal run not copied yet
+        let mut start = 0;
+        let mut i = 0;
+        while i + 1 < fmt.len() {
+            if fmt[i] != b'%' {
+                i += 1;
+                continue;
+            }
+
+            self.push_slice(&fmt[start..i]);
+            let directive = fmt[i + 1];
+            i += 2;
+            start = i;
+
+            let mut buf = [0u8; LONG_STR_SIZE];
+            let value: &[u8] = match directive {
+                b's' | b'S' | b'i' | b'I' | b'u' | b'U' => {
+                    let arg = args.next().unwrap_or_else(|| {
+                        panic!("cat_fmt: no argument left for %{}", directive as char)
+                    });
+                    match (directive, *arg) {
+                        (b's', FmtArg::Str(s)) => s,
+                        (b'S', FmtArg::Sds(s)) => s.as_slice(),
+                        (b'i', FmtArg::Int(v)) => sds_ll2str(&mut buf, v as i64),
+                        (b'I', FmtArg::Long(v)) => sds_ll2str(&mut buf, v),
+                        (b'u', FmtArg::UInt(v)) => sds_ull2str(&mut buf, v as u64),
+                        (b'U', FmtArg::ULong(v)) => sds_ull2str(&mut buf, v),
+                        (_, arg) => panic!("cat_fmt: %{} does not take {:?}", directive as char, arg),
+                    }
+                }
+                _ => &fmt[i - 1..i],
+            };
+            self.push_slice(value);
         }
+
+        self.push_slice(&fmt[start..]);
+        self
     }
 
-    // same as
-    // inline size_t sdslen(const sds s)
-    #[inline]
-    pub fn len(&self) -> usize {
-        match self.type_code() {
-            SDS_TYPE_8 => SdsHdr8::sds_hdr(self).sds_len(),
-            SDS_TYPE_16 => SdsHdr16::sds_hdr(self).sds_len(),
-            SDS_TYPE_32 => SdsHdr32::sds_hdr(self).sds_len(),
-            SDS_TYPE_64 => SdsHdr64::sds_hdr(self).sds_len(),
-            flags => unimplemented!("flags unknown: {}", flags),
+    /// same as
+    ///
+    /// sds sdscatrepr(sds s, const char *p, size_t len)
+    ///
+    /// Appends `p` double quoted, with \\ \" \n \r \t \a \b escaped and any
+    /// other non printable byte written as \xHH, so binary data can be shown
+    /// or logged as is.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::Sds;
+    ///
+    /// let mut s = Sds::empty();
+    /// s.cat_repr(b"a\"b\n\x01");
+    /// assert_eq!(s.as_slice(), b"\"a\\\"b\\n\\x01\"");
+    /// ```
+    pub fn cat_repr(&mut self, p: &[u8]) -> &mut Self {
+        self.make_room_for(p.len() + 2);
+        self.push_u8(b'"');
+        let mut buf = [0u8; 4];
+        for &c in p {
+            self.push_slice(repr_escape(c, &mut buf));
         }
+        self.push_u8(b'"')
     }
 
-    // same as
-    // inline size_t sdsavail(const sds s)
-    #[inline]
-    pub fn alloc(&self) -> usize {
-        match self.type_code() {
-            SDS_TYPE_8 => SdsHdr8::sds_hdr(self).sds_alloc(),
-            SDS_TYPE_16 => SdsHdr16::sds_hdr(self).sds_alloc(),
-            SDS_TYPE_32 => SdsHdr32::sds_hdr(self).sds_alloc(),
-            SDS_TYPE_64 => SdsHdr64::sds_hdr(self).sds_alloc(),
+    /// same as
+    ///
+    /// int sdsneedsrepr(const_sds s)
+    ///
+    /// Tells if the content has to go through `cat_repr` to be shown, that
+    /// is if it has anything escaped or any space.
+    pub fn needs_repr(&self) -> bool {
+        self.as_slice().iter().any(|&c| matches!(c, b'\\' | b'"') || !is_print(c) || is_space(c))
+    }
+
+    /// same as
+    ///
+    /// sds *sdssplitargs(const char *line, int *argc)
+    ///
+    /// Splits a line into arguments the way a shell would, as used for
+    /// redis.conf lines, inline commands and redis-cli input. Arguments are
+    /// separated by spaces and can be:
+    ///
+    /// * plain: `foo`
+    /// * double quoted: `"foo bar"`, with the escapes \xHH \n \r \t \b \a,
+    ///   any other escaped character stands for itself
+    /// * single quoted: `'foo bar'`, where only \' is an escape
+    ///
+    /// A closing quote must be followed by a space or the end of the line.
+    /// An empty or blank line gives no argument.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::{Sds, ParseError};
+    ///
+    /// let args = Sds::split_args(b"set \"k\\x41\" 'it\\'s'").unwrap();
+    /// assert_eq!(args, [Sds::from_str("set"), Sds::from_str("kA"), Sds::from_str("it's")]);
+    /// assert_eq!(Sds::split_args(b"get \"k"), Err(ParseError::UnbalancedQuotes));
+    /// ```
+    pub fn split_args(line: &[u8]) -> Result<Vec<Sds>, ParseError> {
+        let at = |i: usize| line.get(i).copied();
+        let mut args = Vec::new();
+        let mut p = 0;
+        loop {
+            // skip blanks
+            while at(p).is_some_and(is_space) {
+                p += 1;
+            }
+            if p == line.len() {
+                return Ok(args);
+            }
+
+            let mut in_quotes = false;
+            let mut in_single_quotes = false;
+            let mut current = Sds::empty();
+            loop {
+                let c = at(p);
+                if in_quotes {
+                    match c {
+                        Some(b'\\') if at(p + 1) == Some(b'x')
+                            && at(p + 2).is_some_and(is_hex_digit)
+                            && at(p + 3).is_some_and(is_hex_digit) => {
+                            current.push_u8(hex_digit_to_int(line[p + 2]) * 16 + hex_digit_to_int(line[p + 3]));
+                            p += 3;
+                        }
+                        Some(b'\\') if at(p + 1).is_some() => {
+                            p += 1;
+                            current.push_u8(match line[p] {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                c => c,
+                            });
+                        }
+                        Some(b'"') => {
+                            if at(p + 1).is_some_and(|c| !is_space(c)) {
+                                return Err(ParseError::TrailingAfterQuote);
+                            }
+                            p += 1;
+                            break;
+                        }
+                        Some(c) => {
+                            current.push_u8(c);
+                        }
+                        None => return Err(ParseError::UnbalancedQuotes),
+                    }
+                } else if in_single_quotes {
+                    match c {
+                        Some(b'\\') if at(p + 1) == Some(b'\'') => {
+                            p += 1;
+                            current.push_u8(b'\'');
+                        }
+                        Some(b'\'') => {
+                            if at(p + 1).is_some_and(|c| !is_space(c)) {
+                                return Err(ParseError::TrailingAfterQuote);
+                            }
+                            p += 1;
+                            break;
+                        }
+                        Some(c) => {
+                            current.push_u8(c);
+                        }
+                        None => return Err(ParseError::UnbalancedQuotes),
+                    }
+                } else {
+                    match c {
+                        None | Some(b' ' | b'\n' | b'\r' | b'\t') => break,
+                        Some(b'"') => in_quotes = true,
+                        Some(b'\'') => in_single_quotes = true,
+                        Some(c) => {
+                            current.push_u8(c);
+                        }
+                    }
+                }
+                p += 1;
+            }
+            args.push(current);
+        }
+    }
+
+    /// The free space after the content, `alloc() - len()` bytes, to be
+    /// filled in place, e.g. by `read(2)` on a socket, then committed with
+    /// `advance_len`. Use `reserve` first to get the room.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::Sds;
+    ///
+    /// let mut query_buf = Sds::empty();
+    /// query_buf.reserve(16 * 1024);
+    ///
+    /// let spare = query_buf.spare_capacity_mut();
+    /// // read(fd, spare.as_mut_ptr() as *mut u8, spare.len()) goes here
+    /// let n = 6;
+    /// for (dst, &src) in spare.iter_mut().zip(b"PING\r\n") {
+    ///     dst.write(src);
+    /// }
+    /// unsafe { query_buf.advance_len(n); }
+    /// assert_eq!(query_buf, "PING\r\n");
+    /// ```
+    pub fn spare_capacity_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        let avail = self.avail();
+        unsafe {
+            let spare = self.0.add(self.len()) as *mut MaybeUninit<u8>;
+            std::slice::from_raw_parts_mut(spare, avail)
+        }
+    }
+
+    /// same as
+    ///
+    /// void sdsIncrLen(sds s, ssize_t incr)
+    ///
+    /// Grows the len by `n`, taking in the first `n` bytes of
+    /// `spare_capacity_mut` as content.
+    ///
+    /// # Safety
+    ///
+    /// Those `n` bytes must have been initialized.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is more than the free space, `alloc() - len()`.
+    pub unsafe fn advance_len(&mut self, n: usize) {
+        assert!(n <= self.avail(), "advance_len: {} is more than the {} bytes free", n, self.avail());
+        if n > 0 {
+            self.inc_len_uncheck(n);
+        }
+    }
+
+    /// same as
+    ///
+    /// sds sdstemplate(const char *template, sdstemplate_callback_t cb_func, void *cb_arg)
+    ///
+    /// Expands every `{name}` of `fmt` with the value `var` gives for `name`.
+    /// `{{` and `}}` stand for a literal brace. Fails on a variable `var`
+    /// returns None for and on a brace left open or never opened.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::{Sds, TemplateError};
+    ///
+    /// let conf = Sds::template(b"port {port} # {{ok}}", |name| match name {
+    ///     b"port" => Some(Sds::from_i64(6379)),
+    ///     _ => None,
+    /// });
+    /// assert_eq!(conf.unwrap(), "port 6379 # {ok}");
+    ///
+    /// let err = Sds::template(b"{nope}", |_| None).unwrap_err();
+    /// assert_eq!(err, TemplateError::UnknownVariable(Sds::from_str("nope")));
+    /// ```
+    pub fn template<F>(fmt: &[u8], mut var: F) -> Result<Sds, TemplateError>
+    where
+        F: FnMut(&[u8]) -> Option<Sds>,
+    {
+        let mut res = Sds::empty();
+        let mut p = 0;
+        while p < fmt.len() {
+            // copy everything up to the next brace
+            let rest = &fmt[p..];
+            let brace = match rest.iter().position(|&c| c == b'{' || c == b'}') {
+                Some(brace) => brace,
+                None => {
+                    res.push_slice(rest);
+                    break;
+                }
+            };
+            res.push_slice(&rest[..brace]);
+
+            let after = &rest[brace + 1..];
+            if after.first() == Some(&rest[brace]) {
+                res.push_u8(rest[brace]);
+                p += brace + 2;
+                continue;
+            }
+            if rest[brace] == b'}' {
+                return Err(TemplateError::UnbalancedBraces);
+            }
+
+            let end = after.iter().position(|&c| c == b'{' || c == b'}')
+                .filter(|&end| after[end] == b'}')
+                .ok_or(TemplateError::UnbalancedBraces)?;
+            let name = &after[..end];
+            let value = var(name).ok_or_else(|| TemplateError::UnknownVariable(Sds::from_slice(name)))?;
+            res.push(&value);
+            p += brace + 1 + end + 1;
+        }
+        Ok(res)
+    }
+
+    /// same as
+    ///
+    /// sds sdstrim(sds s, const char *cset)
+    ///
+    /// Removes from both ends every byte found in `cset`, in place.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::Sds;
+    ///
+    /// let mut s = Sds::from_str("AA...AA.a.aa.aHelloWorld     :::");
+    /// s.trim(b"Aa. :");
+    /// assert_eq!(s.as_slice(), b"HelloWorld");
+    /// ```
+    pub fn trim(&mut self, cset: &[u8]) -> &mut Self {
+        let slice = self.as_slice();
+        let start = slice.iter().position(|c| !cset.contains(c)).unwrap_or(slice.len());
+        let end = slice.iter().rposition(|c| !cset.contains(c)).map_or(start, |end| end + 1);
+        self.sub_str(start, end - start)
+    }
+
+    /// same as
+    ///
+    /// void sdsrange(sds s, ssize_t start, ssize_t end)
+    ///
+    /// Keeps only the bytes from `start` to `end`, both included, in place.
+    /// Negative indexes count from the end, -1 being the last byte. Indexes
+    /// out of range are clamped, and if `start` ends up after `end` the sds
+    /// is left empty, the way GETRANGE behaves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::Sds;
+    ///
+    /// let mut s = Sds::from_str("Hello World");
+    /// s.range(1, -1);
+    /// assert_eq!(s.as_slice(), b"ello World");
+    /// s.range(-5, 100);
+    /// assert_eq!(s.as_slice(), b"World");
+    /// ```
+    pub fn range(&mut self, start: isize, end: isize) -> &mut Self {
+        let len = self.len();
+        if len == 0 {
+            return self;
+        }
+
+        let clamp = |index: isize| -> usize {
+            if index < 0 {
+                len.saturating_sub(index.unsigned_abs())
+            } else {
+                index as usize
+            }
+        };
+        let (start, end) = (clamp(start), clamp(end));
+        let new_len = if start > end { 0 } else { end - start + 1 };
+        self.sub_str(start, new_len)
+    }
+
+    /// same as
+    ///
+    /// void sdssubstr(sds s, size_t start, size_t len)
+    ///
+    /// Keeps only `len` bytes from `start`, in place. Both are clamped to the
+    /// current content.
+    pub fn sub_str(&mut self, start: usize, len: usize) -> &mut Self {
+        let old_len = self.len();
+        let (start, len) = if start >= old_len {
+            (0, 0)
+        } else {
+            (start, len.min(old_len - start))
+        };
+        if len == old_len {
+            return self;
+        }
+
+        self.as_mut_slice().copy_within(start..start + len, 0);
+        unsafe { self.set_len_uncheck(len); }
+        self
+    }
+
+    /// same as
+    ///
+    /// sds sdsmapchars(sds s, const char *from, const char *to, size_t setlen)
+    ///
+    /// Replaces in place every byte found in `from` by the byte at the same
+    /// position in `to`, e.g. mapping `b"ho"` to `b"01"` turns "hello" into
+    /// "0ell1".
+    ///
+    /// # Panics
+    ///
+    /// If `from` and `to` are not the same length.
+    pub fn map_chars(&mut self, from: &[u8], to: &[u8]) -> &mut Self {
+        assert_eq!(from.len(), to.len(), "map_chars: from and to differ in length");
+        for c in self.as_mut_slice() {
+            if let Some(i) = from.iter().position(|f| f == c) {
+                *c = to[i];
+            }
+        }
+        self
+    }
+
+    /// same as
+    ///
+    /// void sdstolower(sds s)
+    pub fn to_lower(&mut self) -> &mut Self {
+        self.as_mut_slice().make_ascii_lowercase();
+        self
+    }
+
+    /// same as
+    ///
+    /// void sdstoupper(sds s)
+    pub fn to_upper(&mut self) -> &mut Self {
+        self.as_mut_slice().make_ascii_uppercase();
+        self
+    }
+
+    /// same as
+    ///
+    /// sds *sdssplitlen(const char *s, ssize_t len, const char *sep, int seplen, int *count)
+    ///
+    /// Splits `data` on every occurrence of `sep`, which can be more than one
+    /// byte long. Both are binary safe. Two separators in a row give an empty
+    /// piece, empty `data` or an empty `sep` gives no piece at all.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use redis_rust_copy::Sds;
+    ///
+    /// let parts = Sds::split_len(b"a--b----c", b"--");
+    /// assert_eq!(parts, [Sds::from_str("a"), Sds::from_str("b"), Sds::empty(), Sds::from_str("c")]);
+    /// ```
+    pub fn split_len(data: &[u8], sep: &[u8]) -> Vec<Sds> {
+        let mut parts = Vec::new();
+        if data.is_empty() || sep.is_empty() {
+            return parts;
+        }
+
+        let mut start = 0;
+        let mut j = 0;
+        while j + sep.len() <= data.len() {
+            if data[j..j + sep.len()] == *sep {
+                parts.push(Sds::from_slice(&data[start..j]));
+                start = j + sep.len();
+                j = start;
+            } else {
+                j += 1;
+            }
+        }
+        parts.push(Sds::from_slice(&data[start..]));
+        parts
+    }
+
+    /// same as
+    ///
+    /// sds sdsjoinsds(sds *argv, int argc, const char *sep, size_t seplen)
+    ///
+    /// Concatenates `strings` with `sep` in between, allocating only once.
+    pub fn join(strings: &[Sds], sep: &[u8]) -> Sds {
+        Self::join_slices(strings.iter().map(|s| s.as_slice()), strings.len(), sep)
+    }
+
+    /// same as
+    ///
+    /// sds sdsjoin(char **argv, int argc, char *sep)
+    ///
+    /// `join` for plain strings.
+    pub fn join_str(strings: &[&str], sep: &str) -> Sds {
+        Self::join_slices(strings.iter().map(|s| s.as_bytes()), strings.len(), sep.as_bytes())
+    }
+
+    fn join_slices<'a, I>(strings: I, count: usize, sep: &[u8]) -> Sds
+    where
+        I: Iterator<Item = &'a [u8]> + Clone,
+    {
+        let total = strings.clone().map(|s| s.len()).sum::<usize>()
+            + sep.len() * count.saturating_sub(1);
+        let mut joined = Self::with_capacity(total);
+        for (i, s) in strings.enumerate() {
+            if i > 0 {
+                joined.push_slice(sep);
+            }
+            joined.push_slice(s);
+        }
+        joined
+    }
+
+    #[inline]
+    fn type_code(&self) -> u8 {
+        unsafe {
+            *self.0.sub(1) & SDS_TYPE_MASK
+        }
+    }
+
+    #[inline]
+    fn flags(&self) -> u8 {
+        unsafe {
+            *self.0.sub(1)
+        }
+    }
+
+    // bytes before the header, the reference count of a shared block
+    #[inline]
+    fn prefix_size(&self) -> usize {
+        let flags = self.flags();
+        if flags & SDS_TYPE_MASK != SDS_TYPE_5 && flags & SDS_RC != 0 {
+            SDS_RC_SIZE
+        } else {
+            0
+        }
+    }
+
+    // start of the allocated block, what s_malloc returned
+    #[inline]
+    fn block(&self) -> *const u8 {
+        unsafe {
+            self.0.sub(sds_hdr_size(self.type_code()) + self.prefix_size())
+        }
+    }
+
+    // the reference count in front of the header, None unless the sds was
+    // made by try_from_slice_shared
+    #[inline]
+    pub(crate) fn ref_count(&self) -> Option<&AtomicUsize> {
+        if self.prefix_size() == 0 {
+            return None;
+        }
+        unsafe { Some(&*(self.block() as *const AtomicUsize)) }
+    }
+
+    #[inline]
+    unsafe fn set_type_5_len(&mut self, len: usize) {
+        debug_assert!(len <= sds_type_max_size(SDS_TYPE_5));
+        *(self.0.sub(1) as *mut u8) = SDS_TYPE_5 | ((len as u8) << SDS_TYPE_BITS);
+    }
+
+    // same as
+    // inline size_t sdslen(const sds s)
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self.type_code() {
+            SDS_TYPE_5 => sds_type_5_len(self.flags()),
+            SDS_TYPE_8 => SdsHdr8::sds_hdr(self).sds_len(),
+            SDS_TYPE_16 => SdsHdr16::sds_hdr(self).sds_len(),
+            SDS_TYPE_32 => SdsHdr32::sds_hdr(self).sds_len(),
+            SDS_TYPE_64 => SdsHdr64::sds_hdr(self).sds_len(),
+            flags => unimplemented!("flags unknown: {}", flags),
+        }
+    }
+
+    // same as
+    // inline size_t sdsavail(const sds s)
+    #[inline]
+    pub fn alloc(&self) -> usize {
+        match self.type_code() {
+            SDS_TYPE_5 => sds_type_5_len(self.flags()),
+            SDS_TYPE_8 => SdsHdr8::sds_hdr(self).sds_alloc(),
+            SDS_TYPE_16 => SdsHdr16::sds_hdr(self).sds_alloc(),
+            SDS_TYPE_32 => SdsHdr32::sds_hdr(self).sds_alloc(),
+            SDS_TYPE_64 => SdsHdr64::sds_hdr(self).sds_alloc(),
             flags => unimplemented!("flags unknown: {}", flags)
         }
     }
@@ -387,6 +1459,7 @@ impl Sds {
     #[inline]
     fn avail(&self) -> usize {
         match self.type_code() {
+            SDS_TYPE_5 => 0,
             SDS_TYPE_8 => SdsHdr8::sds_hdr(self).sds_avail(),
             SDS_TYPE_16 => SdsHdr16::sds_hdr(self).sds_avail(),
             SDS_TYPE_32 => SdsHdr32::sds_hdr(self).sds_avail(),
@@ -401,6 +1474,7 @@ impl Sds {
     #[inline]
     unsafe fn set_len_uncheck(&mut self, new_len: usize) {
         match self.type_code() {
+            SDS_TYPE_5 => self.set_type_5_len(new_len),
             SDS_TYPE_8 => SdsHdr8::mut_sds_hdr(self).len = new_len as u8,
             SDS_TYPE_16 => SdsHdr16::mut_sds_hdr(self).len = new_len as u16,
             SDS_TYPE_32 => SdsHdr32::mut_sds_hdr(self).len = new_len as u32,
@@ -411,10 +1485,11 @@ impl Sds {
 
     // same as
     // inline void sdsinclen(sds s, size_t inc)
-    // but mark unsafe
+    // but mark unsafe, len() + inc must not exceed alloc()
     #[inline]
-    pub unsafe fn inc_len_uncheck(&mut self, inc: usize) {
+    unsafe fn inc_len_uncheck(&mut self, inc: usize) {
         match self.type_code() {
+            SDS_TYPE_5 => self.set_type_5_len(sds_type_5_len(self.flags()) + inc),
             SDS_TYPE_8 => SdsHdr8::mut_sds_hdr(self).len += inc as u8,
             SDS_TYPE_16 => SdsHdr16::mut_sds_hdr(self).len += inc as u16,
             SDS_TYPE_32 => SdsHdr32::mut_sds_hdr(self).len += inc as u32,
@@ -429,6 +1504,8 @@ impl Sds {
     #[inline]
     unsafe fn set_alloc_uncheck(&mut self, alloc: usize) {
         match self.type_code() {
+            // nothing to do, type 5 has no alloc
+            SDS_TYPE_5 => {}
             SDS_TYPE_8 => SdsHdr8::mut_sds_hdr(self).alloc = alloc as u8,
             SDS_TYPE_16 => SdsHdr16::mut_sds_hdr(self).alloc = alloc as u16,
             SDS_TYPE_32 => SdsHdr32::mut_sds_hdr(self).alloc = alloc as u32,
@@ -438,15 +1515,41 @@ impl Sds {
     }
 }
 
+// invalid utf8 is shown as U+FFFD, like to_str_lossy, which only allocates
+// if there is something to replace. Either way through pad, so width, fill
+// and alignment apply whatever the bytes.
+pub(crate) fn fmt_lossy(bytes: &[u8], f: &mut Formatter<'_>) -> fmt::Result {
+    f.pad(&String::from_utf8_lossy(bytes))
+}
+
+// quoted and escaped the way cat_repr does, so any byte can be shown
+pub(crate) fn fmt_repr(bytes: &[u8], f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_str("\"")?;
+    let mut buf = [0u8; 4];
+    for &c in bytes {
+        let escaped = repr_escape(c, &mut buf);
+        f.write_str(std::str::from_utf8(escaped).map_err(|_| fmt::Error)?)?;
+    }
+    f.write_str("\"")
+}
+
 impl Display for Sds {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.as_str_uncheck())
+        fmt_lossy(self.as_slice(), f)
     }
 }
 
 impl Debug for Sds {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        Display::fmt(self, f)
+        fmt_repr(self.as_slice(), f)
+    }
+}
+
+impl fmt::Write for Sds {
+    // write! appends right into the buffer, a failed allocation is reported
+    // as fmt::Error and leaves the sds untouched
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.try_push_slice(s.as_bytes()).map(|_| ()).map_err(|_| fmt::Error)
     }
 }
 
@@ -460,7 +1563,7 @@ impl Eq for Sds {}
 
 impl PartialOrd for Sds {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.as_slice().partial_cmp(other.as_slice())
+        Some(self.cmp(other))
     }
 }
 
@@ -470,6 +1573,112 @@ impl Ord for Sds {
     }
 }
 
+impl PartialEq<[u8]> for Sds {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl PartialEq<&[u8]> for Sds {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.as_slice() == *other
+    }
+}
+
+impl PartialEq<str> for Sds {
+    fn eq(&self, other: &str) -> bool {
+        self.as_slice() == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for Sds {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_slice() == other.as_bytes()
+    }
+}
+
+// must agree with the hash of [u8] for Borrow
+impl Hash for Sds {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state);
+    }
+}
+
+impl Borrow<[u8]> for Sds {
+    fn borrow(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Sds {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Default for Sds {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl From<&str> for Sds {
+    fn from(s: &str) -> Self {
+        Self::from_str(s)
+    }
+}
+
+impl From<&[u8]> for Sds {
+    fn from(s: &[u8]) -> Self {
+        Self::from_slice(s)
+    }
+}
+
+// the buffer comes from a different allocator and has no room for the
+// header, so it is copied
+impl From<String> for Sds {
+    fn from(s: String) -> Self {
+        Self::from_str(&s)
+    }
+}
+
+impl From<Vec<u8>> for Sds {
+    fn from(v: Vec<u8>) -> Self {
+        Self::from_slice(&v)
+    }
+}
+
+impl Extend<u8> for Sds {
+    fn extend<I: IntoIterator<Item = u8>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.make_room_for(iter.size_hint().0);
+        for c in iter {
+            self.push_u8(c);
+        }
+    }
+}
+
+impl FromIterator<u8> for Sds {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        let mut sds = Self::empty();
+        sds.extend(iter);
+        sds
+    }
+}
+
+impl io::Write for Sds {
+    // a failed allocation is reported as OutOfMemory and leaves the sds
+    // untouched
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.try_push_slice(buf).map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 impl Deref for Sds {
     type Target = [u8];
 
@@ -488,16 +1697,12 @@ impl Clone for Sds {
     // same as
     // sds sdsdup(const sds s)
     fn clone(&self) -> Self {
-        Self::from_raw_pointer(self.0, self.len(), false)
+        Self::from_raw_pointer(self.0, self.len())
     }
 
     fn clone_from(&mut self, source: &Self) {
-        let s = source.clone();
-        {
-            // trigger drop
-            let _ = Sds(self.0);
-        }
-        self.0 = s.0;
+        // the assignment drops the old sds
+        *self = source.clone();
     }
 }
 
@@ -507,7 +1712,7 @@ impl Drop for Sds {
     fn drop(&mut self) {
         if !self.is_global_empty() {
             unsafe {
-                s_free(self.0.offset(-(sds_hdr_size(self.type_code()) as isize)));
+                s_free(self.block());
             }
         }
     }
@@ -516,6 +1721,7 @@ impl Drop for Sds {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::util::string2ll;
 
     macro_rules! test_sds_base {
         ($kind:ident, $flag:expr) => {
@@ -527,7 +1733,7 @@ mod test {
         };
         let p = (&hdr as *const $kind) as *const u8;
         unsafe {
-            let mut sds = Sds(p.offset(std::mem::size_of_val(&hdr) as isize));
+            let mut sds = Sds(p.add(std::mem::size_of_val(&hdr)));
             assert_eq!(sds.len(), 0, "{} init len assert fail", stringify!($kind));
             assert_eq!(sds.alloc(), 0, "{} init alloc assert fail", stringify!($kind));
             assert_eq!(sds.avail(), 0, "{} init avail assert fail", stringify!($kind));
@@ -564,7 +1770,7 @@ mod test {
         let mut my_string = Sds::from_str("Hello World!");
         println!("{}", my_string);
 
-        let buf = ['A' as u8, 'B' as u8, 'C' as u8];
+        let buf = [b'A', b'B', b'C'];
         my_string = Sds::from_slice(&buf); // auto free before value
         println!("{} of len {}", my_string, my_string.len());
 
@@ -578,8 +1784,387 @@ mod test {
         println!("{} == {}", my_string, my_string2);
 
         my_string = Sds::from_str(" Hello World! ");
-        let my_string_trim = my_string.as_str_uncheck().trim();
+        let my_string_trim = my_string.as_str().unwrap().trim();
         println!("{}", my_string_trim);
-        println!("{} {}", my_string.starts_with(&[' ' as u8]), my_string_trim.starts_with('H'));
+        println!("{} {}", my_string.starts_with(b" "), my_string_trim.starts_with('H'));
+    }
+
+    #[test]
+    fn test_from_number() {
+        assert_eq!(Sds::from_i64(0).as_slice(), b"0");
+        assert_eq!(Sds::from_i64(-1).as_slice(), b"-1");
+        assert_eq!(Sds::from_i64(i64::MIN).as_slice(), i64::MIN.to_string().as_bytes());
+        assert_eq!(Sds::from_i64(i64::MAX).as_slice(), i64::MAX.to_string().as_bytes());
+        assert_eq!(Sds::from_u64(u64::MAX).as_slice(), u64::MAX.to_string().as_bytes());
+        assert_eq!(string2ll(&Sds::from_i64(-42)), Some(-42));
+    }
+
+    #[test]
+    fn test_cat_fmt() {
+        let mut s = Sds::empty();
+        s.cat_fmt("%s %S", &["Hello".into(), (&Sds::from_str("World")).into()]);
+        assert_eq!(s.as_slice(), b"Hello World");
+
+        let mut s = Sds::from_str("--");
+        s.cat_fmt("%i,%I,%u,%U--", &[
+            i32::MIN.into(), i64::MIN.into(), u32::MAX.into(), u64::MAX.into(),
+        ]);
+        assert_eq!(s.as_str().unwrap(), format!("--{},{},{},{}--", i32::MIN, i64::MIN, u32::MAX, u64::MAX));
+
+        let mut s = Sds::empty();
+        s.cat_fmt("100%% %x %", &[]);
+        assert_eq!(s.as_slice(), b"100% x %");
+
+        let mut s = Sds::empty();
+        s.cat_fmt("%s", &[FmtArg::Str(&[0, 0xff])]);
+        assert_eq!(s.as_slice(), &[0, 0xff]);
+
+        let missing = std::panic::catch_unwind(|| Sds::empty().cat_fmt("%i %i", &[1.into()]).len());
+        assert!(missing.is_err());
+        let mismatch = std::panic::catch_unwind(|| Sds::empty().cat_fmt("%i", &[1u32.into()]).len());
+        assert!(mismatch.is_err());
+    }
+
+    #[test]
+    fn test_cat_repr() {
+        let mut s = Sds::from_str("x=");
+        s.cat_repr(b"\x07\n\x001234\x80\\\"\r\t\x08 ~");
+        assert_eq!(s.as_slice(), &b"x=\"\\a\\n\\x001234\\x80\\\\\\\"\\r\\t\\b ~\""[..]);
+
+        let mut s = Sds::empty();
+        s.cat_repr(b"");
+        assert_eq!(s.as_slice(), b"\"\"");
+
+        assert!(!Sds::from_str("plain-text_1").needs_repr());
+        assert!(!Sds::empty().needs_repr());
+        assert!(Sds::from_str("two words").needs_repr());
+        assert!(Sds::from_str("quo\"te").needs_repr());
+        assert!(Sds::from_str("back\\slash").needs_repr());
+        assert!(Sds::from_slice(&[b'a', 0xff]).needs_repr());
+        assert!(Sds::from_str("\x0b").needs_repr());
+    }
+
+    #[test]
+    fn test_split_args() {
+        fn split(line: &str) -> Result<Vec<String>, ParseError> {
+            Sds::split_args(line.as_bytes())
+                .map(|args| args.iter().map(|a| a.to_string()).collect())
+        }
+
+        assert_eq!(split("").unwrap(), Vec::<String>::new());
+        assert_eq!(split(" \t\r\n ").unwrap(), Vec::<String>::new());
+        assert_eq!(split("  set key  value ").unwrap(), ["set", "key", "value"]);
+        assert_eq!(split("a\tb\nc").unwrap(), ["a", "b", "c"]);
+        assert_eq!(split("save \"\"").unwrap(), ["save", ""]);
+        assert_eq!(split("\"a b\\n\\r\\t\\\"\\\\\\z\" c").unwrap(), ["a b\n\r\t\"\\z", "c"]);
+        assert_eq!(split("\"\\x41\\x6a\\xZZ\\x4\"").unwrap(), ["AjxZZx4"]);
+        assert_eq!(split("'a \"b\" \\'c\\' \\n'").unwrap(), ["a \"b\" 'c' \\n"]);
+        assert_eq!(split("foo\"bar\"").unwrap(), ["foobar"]);
+
+        assert_eq!(split("\"abc"), Err(ParseError::UnbalancedQuotes));
+        assert_eq!(split("'abc"), Err(ParseError::UnbalancedQuotes));
+        assert_eq!(split("\"abc\\\""), Err(ParseError::UnbalancedQuotes));
+        assert_eq!(split("\"a\"b"), Err(ParseError::TrailingAfterQuote));
+        assert_eq!(split("'a'b"), Err(ParseError::TrailingAfterQuote));
+
+        let args = Sds::split_args(b"\"\\x00\\xff\"").unwrap();
+        assert_eq!(args[0].as_slice(), &[0, 0xff]);
+    }
+
+    #[test]
+    fn test_split_len_join() {
+        fn split(data: &[u8], sep: &[u8]) -> Vec<Vec<u8>> {
+            Sds::split_len(data, sep).iter().map(|s| s.as_slice().to_vec()).collect()
+        }
+
+        assert_eq!(split(b"a,b,,c", b","), [&b"a"[..], b"b", b"", b"c"]);
+        assert_eq!(split(b",a,", b","), [&b""[..], b"a", b""]);
+        assert_eq!(split(b"abc", b"abcd"), [b"abc"]);
+        assert_eq!(split(b"a_-_b_-", b"_-_"), [&b"a"[..], b"b_-"]);
+        assert_eq!(split(b"\0\xff\0", b"\xff"), [b"\0", b"\0"]);
+        assert!(split(b"", b",").is_empty());
+        assert!(split(b"abc", b"").is_empty());
+
+        let parts = Sds::split_len(b"x\r\ny\r\n\xfe", b"\r\n");
+        let joined = Sds::join(&parts, b"\r\n");
+        assert_eq!(joined.as_slice(), b"x\r\ny\r\n\xfe");
+
+        assert_eq!(Sds::join(&[], b",").as_slice(), b"");
+        assert_eq!(Sds::join(&[Sds::empty(), Sds::empty()], b"").as_slice(), b"");
+        assert_eq!(Sds::join_str(&["save", "900", "1"], " ").as_slice(), b"save 900 1");
+        assert_eq!(Sds::join_str(&["only"], ", ").as_slice(), b"only");
+    }
+
+    #[test]
+    fn test_in_place_edit() {
+        let mut s = Sds::from_str("xxciaoyyy");
+        let ptr = s.0;
+        s.trim(b"xy");
+        assert_eq!(s.as_slice(), b"ciao");
+        s.trim(b"ciao");
+        assert!(s.is_empty());
+        assert_eq!(s.0, ptr);
+
+        let mut s = Sds::from_str(" \t\r\n ");
+        s.trim(b" \t\r\n");
+        assert!(s.is_empty());
+        Sds::empty().trim(b"").clear();
+
+        let cases: &[(isize, isize, &[u8])] = &[
+            (1, 1, b"i"), (-2, -1, b"ao"), (2, 1, b""), (1, 100, b"iao"),
+            (100, 100, b""), (0, 0, b"c"), (-100, -3, b"ci"), (-1, -100, b""),
+        ];
+        for &(start, end, expected) in cases {
+            let mut s = Sds::from_str("ciao");
+            s.range(start, end);
+            assert_eq!(s.as_slice(), expected, "range({}, {})", start, end);
+        }
+        Sds::empty().range(0, -1);
+
+        let mut s = Sds::from_str("ciao");
+        assert_eq!(s.sub_str(1, 2).as_slice(), b"ia");
+        assert_eq!(s.sub_str(1, 100).as_slice(), b"a");
+        assert_eq!(s.sub_str(5, 1).as_slice(), b"");
+
+        let mut s = Sds::from_str("hello");
+        s.map_chars(b"ho", b"01");
+        assert_eq!(s.as_slice(), b"0ell1");
+        s.map_chars(b"", b"");
+        assert_eq!(s.as_slice(), b"0ell1");
+
+        let mut s = Sds::from_slice(b"SeT\xc9k1");
+        assert_eq!(s.to_lower().as_slice(), b"set\xc9k1");
+        assert_eq!(s.to_upper().as_slice(), b"SET\xc9K1");
+    }
+
+    #[test]
+    fn test_sds_type_5() {
+        let mut s = Sds::from_slice(&[b'a'; 31]);
+        assert_eq!(s.type_code(), SDS_TYPE_5);
+        assert_eq!(s.len(), 31);
+        assert_eq!(s.alloc(), 31);
+        assert_eq!(s.avail(), 0);
+        assert_eq!(s.alloc_size(), sds_hdr_size(SDS_TYPE_5) + 31);
+
+        // same bytes, same way of counting, only the header differs
+        let mut t = Sds::with_capacity(31);
+        t.push_slice(&[b'a'; 31]).remove_free_space(true);
+        assert_eq!(t.type_code(), SDS_TYPE_8);
+        assert_eq!(t.alloc_size() - s.alloc_size(), sds_hdr_size(SDS_TYPE_8) - sds_hdr_size(SDS_TYPE_5));
+        assert_eq!(Sds::from_slice(&[b'a'; 32]).type_code(), SDS_TYPE_8);
+        assert_eq!(Sds::from_i64(-42).type_code(), SDS_TYPE_5);
+
+        // promoted on the first append, which makes room greedily
+        s.push_u8(b'b');
+        assert_eq!(s.type_code(), SDS_TYPE_8);
+        assert_eq!(s.len(), 32);
+        assert!(s.alloc() >= 64);
+        assert_eq!(&s[30..], b"ab");
+
+        let mut s = Sds::from_str("  key ");
+        s.trim(b" ");
+        assert_eq!(s.type_code(), SDS_TYPE_5);
+        assert_eq!(s.as_slice(), b"key");
+        assert_eq!(s.clone().as_slice(), b"key");
+        s.to_upper().clear();
+        assert!(s.is_empty());
+        assert_eq!(s.type_code(), SDS_TYPE_5);
+        s.push_str("grown");
+        assert_eq!(s.as_slice(), b"grown");
+    }
+
+    #[test]
+    fn test_utf8() {
+        let s = Sds::from_str("héllo");
+        assert_eq!(s.as_str(), Ok("héllo"));
+        assert_eq!(s.to_str_lossy(), "héllo");
+        assert!(matches!(s.to_str_lossy(), Cow::Borrowed(_)));
+        assert_eq!(format!("[{:>7}]", s), "[  héllo]");
+        assert_eq!(format!("{:?}", s), "\"h\\xc3\\xa9llo\"");
+
+        let s = Sds::from_slice(b"a\xffb\xc3");
+        assert!(s.as_str().is_err());
+        assert_eq!(s.to_str_lossy(), "a\u{FFFD}b\u{FFFD}");
+        assert_eq!(s.to_string(), "a\u{FFFD}b\u{FFFD}");
+        assert_eq!(format!("[{:>5}]", Sds::from_slice(b"a\xff")), "[   a\u{FFFD}]");
+        assert_eq!(format!("[{:*<6.3}]", s), "[a\u{FFFD}b***]");
+        assert_eq!(format!("{:?}", s), "\"a\\xffb\\xc3\"");
+        assert_eq!(format!("{:?}", Sds::from_str("\"q\"\n")), "\"\\\"q\\\"\\n\"");
+
+        #[allow(deprecated)]
+        let uncheck = std::panic::catch_unwind(|| s.as_str_uncheck().len());
+        assert!(uncheck.is_err());
+    }
+
+    #[test]
+    fn test_template() {
+        fn vars(name: &[u8]) -> Option<Sds> {
+            match name {
+                b"v1" => Some(Sds::from_str("value1")),
+                b"v2" => Some(Sds::from_slice(b"\0bin")),
+                b"" => Some(Sds::from_str("anon")),
+                _ => None,
+            }
+        }
+
+        let res = Sds::template(b"v1={v1} v2={v2}", vars).unwrap();
+        assert_eq!(res, &b"v1=value1 v2=\0bin"[..]);
+        assert_eq!(Sds::template(b"{v1}{v1}", vars).unwrap(), "value1value1");
+        assert_eq!(Sds::template(b"{}", vars).unwrap(), "anon");
+        assert_eq!(Sds::template(b"", vars).unwrap(), "");
+        assert_eq!(Sds::template(b"no vars", vars).unwrap(), "no vars");
+        assert_eq!(Sds::template(b"{{v1}} {{{v1}}}", vars).unwrap(), "{v1} {value1}");
+
+        let unknown = Sds::template(b"{v1} {v3}", vars).unwrap_err();
+        assert_eq!(unknown, TemplateError::UnknownVariable(Sds::from_str("v3")));
+        assert_eq!(unknown.to_string(), "unknown template variable \"v3\"");
+        for bad in [&b"{v1"[..], b"{", b"v1}", b"}", b"{v{1}", b"{v1}}"] {
+            assert_eq!(Sds::template(bad, vars), Err(TemplateError::UnbalancedBraces), "{:?}", bad);
+        }
+
+        let mut calls = 0;
+        Sds::template(b"{a}{b}", |_| {
+            calls += 1;
+            Some(Sds::empty())
+        }).unwrap();
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn test_spare_capacity() {
+        let mut s = Sds::from_str("head");
+        assert!(s.spare_capacity_mut().is_empty());
+        unsafe { s.advance_len(0); }
+
+        s.reserve(100);
+        let alloc = s.alloc();
+        let spare = s.spare_capacity_mut();
+        assert_eq!(spare.len(), alloc - 4);
+        for (i, c) in spare.iter_mut().take(6).enumerate() {
+            c.write(b'0' + i as u8);
+        }
+        unsafe { s.advance_len(6); }
+        assert_eq!(s, "head012345");
+
+        let avail = s.alloc() - s.len();
+        let overflow = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+            s.advance_len(avail + 1);
+        }));
+        assert!(overflow.is_err());
+        assert_eq!(s, "head012345");
+
+        let mut empty = Sds::empty();
+        assert!(empty.spare_capacity_mut().is_empty());
+        unsafe { empty.advance_len(0); }
+        assert!(empty.is_global_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut s = Sds::from_str("0123456789");
+        let additional = s.alloc() - s.len() + 1;
+        s.reserve(additional);
+        assert!(s.alloc() >= 2 * (10 + additional));
+        s.reserve_exact(100);
+        assert!(s.alloc() >= 110 && s.alloc() < 220);
+        assert_eq!(s.alloc_size(), sds_hdr_size(SDS_TYPE_8) + s.alloc());
+
+        s.remove_free_space(true);
+        assert_eq!(s.as_slice(), b"0123456789");
+        assert_eq!(s.alloc(), 10);
+        assert_eq!(s.alloc_size(), sds_hdr_size(SDS_TYPE_8) + 10);
+
+        s.resize(4, false);
+        assert_eq!(s.as_slice(), b"0123");
+        assert_eq!(s.alloc(), 4);
+        s.resize(1000, true);
+        assert_eq!(s.as_slice(), b"0123");
+        assert_eq!(s.alloc(), 1000);
+        assert_eq!(s.type_code(), SDS_TYPE_16);
+
+        // a big header goes down to type 8 with a new block
+        s.remove_free_space(true);
+        assert_eq!(s.type_code(), SDS_TYPE_8);
+        assert_eq!(s.as_slice(), b"0123");
+        // or to type 5 if it is not going to grow
+        s.resize(3, false);
+        assert_eq!(s.type_code(), SDS_TYPE_5);
+        assert_eq!(s.as_slice(), b"012");
+        s.push_u8(b'3');
+        assert_eq!(s.type_code(), SDS_TYPE_8);
+        assert_eq!(s.as_slice(), b"0123");
+
+        // room past len needs an alloc field, so at least type 8
+        let mut s = Sds::from_str("abc");
+        s.resize(20, false);
+        assert_eq!(s.type_code(), SDS_TYPE_8);
+        assert_eq!(s.alloc(), 20);
+        assert_eq!(s.alloc_size(), sds_hdr_size(SDS_TYPE_8) + 20);
+        assert_eq!(s.spare_capacity_mut().len(), 17);
+        assert_eq!(s.as_slice(), b"abc");
+
+        // but a small saving keeps the header and reallocs
+        let mut s = Sds::from_slice(&[1u8; 70000]);
+        assert_eq!(s.type_code(), SDS_TYPE_32);
+        s.resize(300, false);
+        assert_eq!(s.type_code(), SDS_TYPE_32);
+        assert_eq!(s.alloc(), 300);
+        assert_eq!(s.as_slice(), &[1u8; 300][..]);
+
+        let mut empty = Sds::empty();
+        assert_eq!(empty.alloc_size(), 0);
+        empty.remove_free_space(true);
+        assert!(empty.is_global_empty());
+        empty.resize(5, true);
+        assert!(!empty.is_global_empty());
+        assert_eq!(empty.alloc(), 5);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_make_room_for_fault() {
+        use crate::z_malloc::z_malloc_fail_nth;
+
+        let mut sds = Sds::from_str("fault");
+
+        // type 5 to 8, malloc of the new header fails
+        z_malloc_fail_nth(1);
+        assert!(sds.try_reserve(1).is_err());
+        assert_eq!(sds.as_slice(), b"fault");
+        assert_eq!(sds.type_code(), SDS_TYPE_5);
+
+        sds.reserve(1);
+        let alloc = sds.alloc();
+
+        // same type, realloc fails
+        z_malloc_fail_nth(1);
+        assert!(sds.try_reserve(alloc + 1).is_err());
+        assert_eq!(sds.as_slice(), b"fault");
+        assert_eq!(sds.alloc(), alloc);
+
+        // type 8 to 16, malloc of the new header fails
+        z_malloc_fail_nth(1);
+        assert!(sds.try_push_slice(&[0u8; 300]).is_err());
+        assert_eq!(sds.as_slice(), b"fault");
+        assert_eq!(sds.type_code(), SDS_TYPE_8);
+
+        // the empty sds has nothing to realloc
+        let mut empty = Sds::empty();
+        z_malloc_fail_nth(1);
+        assert!(empty.try_push_slice(b"x").is_err());
+        assert!(empty.is_global_empty());
+
+        z_malloc_fail_nth(1);
+        assert!(Sds::try_from_slice(b"fault").is_err());
+
+        // shrinking to type 5 takes a new block
+        let mut big = Sds::from_slice(&[0u8; 300]);
+        z_malloc_fail_nth(1);
+        assert!(big.try_resize(10, false).is_err());
+        assert_eq!(big.len(), 300);
+        assert_eq!(big.type_code(), SDS_TYPE_16);
+
+        sds.try_push_slice(&[0u8; 300]).unwrap();
+        assert_eq!(sds.len(), 305);
     }
 }
@@ -0,0 +1,321 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, Ordering};
+
+use crate::sds::Sds;
+use crate::z_malloc::{z_malloc_oom as s_malloc_oom, AllocError};
+
+/// A reference counted `Sds`, for strings fanned out to many owners such as
+/// pub/sub payloads and shared replies.
+///
+/// The count lives in the same block as the string, in front of its header,
+/// so `clone` is an increment and no copy. The bytes are copied only when a
+/// clone is changed, through `DerefMut` or `make_mut`, and only if they are
+/// still shared. `SdsRc` derefs to `Sds` for everything that reads.
+///
+/// # Examples
+///
+/// ```
+/// use redis_rust_copy::SdsRc;
+///
+/// let reply = SdsRc::from_str("+OK\r\n");
+/// let mut copy = reply.clone();
+/// assert!(SdsRc::ptr_eq(&reply, &copy));
+/// assert_eq!(reply.ref_count(), 2);
+///
+/// copy.push_str("!");
+/// assert!(!SdsRc::ptr_eq(&reply, &copy));
+/// assert_eq!(reply.as_slice(), b"+OK\r\n");
+/// assert_eq!(copy.as_slice(), b"+OK\r\n!");
+/// ```
+// the inner sds is the shared empty sds or a block made by
+// Sds::try_from_slice_shared. A plain sds may still get in through DerefMut,
+// it is then owned alone and treated as a count of 1.
+pub struct SdsRc(ManuallyDrop<Sds>);
+
+// the count is atomic and the bytes are only written when it is 1
+unsafe impl Send for SdsRc {}
+unsafe impl Sync for SdsRc {}
+
+impl SdsRc {
+    /// The empty string, sharing the global empty sds, no allocation.
+    pub fn empty() -> Self {
+        SdsRc(ManuallyDrop::new(Sds::empty()))
+    }
+
+    pub fn from_slice(init: &[u8]) -> Self {
+        Self::try_from_slice(init).unwrap_or_else(|e| s_malloc_oom(e.size()))
+    }
+
+    pub fn try_from_slice(init: &[u8]) -> Result<Self, AllocError> {
+        if init.is_empty() {
+            return Ok(Self::empty());
+        }
+        Ok(SdsRc(ManuallyDrop::new(Sds::try_from_slice_shared(init)?)))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(init: &str) -> Self {
+        Self::from_slice(init.as_bytes())
+    }
+
+    /// Number of `SdsRc` sharing the string, 1 for the empty string.
+    pub fn ref_count(&self) -> usize {
+        self.0.ref_count().map_or(1, |count| count.load(Ordering::Acquire))
+    }
+
+    /// True if both point to the same bytes.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        std::ptr::eq(this.0.as_slice().as_ptr(), other.0.as_slice().as_ptr())
+    }
+
+    /// The string, copied first if it is shared with another `SdsRc`, so it
+    /// can be changed without the others seeing it.
+    pub fn make_mut(&mut self) -> &mut Sds {
+        self.try_make_mut().unwrap_or_else(|e| s_malloc_oom(e.size()))
+    }
+
+    pub fn try_make_mut(&mut self) -> Result<&mut Sds, AllocError> {
+        let needs_copy = match self.0.ref_count() {
+            Some(count) => count.load(Ordering::Acquire) > 1,
+            // the global empty sds is read only, give it a block of its own
+            None => self.0.is_global_empty(),
+        };
+        if needs_copy {
+            let unique = Sds::try_from_slice_shared(self.0.as_slice())?;
+            drop(std::mem::replace(self, SdsRc(ManuallyDrop::new(unique))));
+        }
+        Ok(&mut self.0)
+    }
+
+    /// The string as a plain `Sds`, copied only if it is still shared.
+    pub fn into_sds(self) -> Sds {
+        if self.ref_count() > 1 {
+            return Sds::clone(&self.0);
+        }
+        let mut this = ManuallyDrop::new(self);
+        unsafe { ManuallyDrop::take(&mut this.0) }
+    }
+}
+
+impl Clone for SdsRc {
+    fn clone(&self) -> Self {
+        match self.0.ref_count() {
+            Some(count) => {
+                // same as Arc, a new reference is made from an existing one
+                let old = count.fetch_add(1, Ordering::Relaxed);
+                // and past isize::MAX clones were leaked, abort before the
+                // count can wrap to 0 and free a block still in use
+                if old > isize::MAX as usize {
+                    std::process::abort();
+                }
+                SdsRc(ManuallyDrop::new(unsafe { std::ptr::read(&*self.0) }))
+            }
+            None if self.0.is_empty() => Self::empty(),
+            // a plain sds put in through DerefMut
+            None => Self::from_slice(self.0.as_slice()),
+        }
+    }
+}
+
+impl Drop for SdsRc {
+    fn drop(&mut self) {
+        if let Some(count) = self.0.ref_count() {
+            if count.fetch_sub(1, Ordering::Release) != 1 {
+                return;
+            }
+            fence(Ordering::Acquire);
+        }
+        unsafe { ManuallyDrop::drop(&mut self.0) }
+    }
+}
+
+impl Deref for SdsRc {
+    type Target = Sds;
+
+    fn deref(&self) -> &Sds {
+        &self.0
+    }
+}
+
+impl DerefMut for SdsRc {
+    fn deref_mut(&mut self) -> &mut Sds {
+        self.make_mut()
+    }
+}
+
+impl From<Sds> for SdsRc {
+    // a block already counted, back from into_sds, is kept, any other sds
+    // is copied behind a count
+    fn from(sds: Sds) -> Self {
+        match sds.ref_count() {
+            Some(count) if count.load(Ordering::Acquire) == 1 => SdsRc(ManuallyDrop::new(sds)),
+            _ => Self::from_slice(sds.as_slice()),
+        }
+    }
+}
+
+impl From<&str> for SdsRc {
+    fn from(s: &str) -> Self {
+        Self::from_str(s)
+    }
+}
+
+impl From<&[u8]> for SdsRc {
+    fn from(s: &[u8]) -> Self {
+        Self::from_slice(s)
+    }
+}
+
+impl Default for SdsRc {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl PartialEq for SdsRc {
+    fn eq(&self, other: &Self) -> bool {
+        *self.0 == *other.0
+    }
+}
+
+impl Eq for SdsRc {}
+
+impl PartialEq<Sds> for SdsRc {
+    fn eq(&self, other: &Sds) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl Hash for SdsRc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl Borrow<[u8]> for SdsRc {
+    fn borrow(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for SdsRc {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+impl fmt::Debug for SdsRc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for SdsRc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alloc_track::LeakCheck;
+
+    #[test]
+    fn test_sds_rc_share() {
+        let check = LeakCheck::new();
+        {
+            let a = SdsRc::from_str("shared reply");
+            let b = a.clone();
+            let c = b.clone();
+            assert!(SdsRc::ptr_eq(&a, &c));
+            assert_eq!(a.ref_count(), 3);
+            assert_eq!(c, a);
+            assert_eq!(a.as_slice(), b"shared reply");
+
+            drop(b);
+            assert_eq!(a.ref_count(), 2);
+            drop(c);
+            assert_eq!(a.ref_count(), 1);
+        }
+        check.assert_no_leaks();
+    }
+
+    #[test]
+    fn test_sds_rc_copy_on_write() {
+        let check = LeakCheck::new();
+        {
+            let a = SdsRc::from_str("payload");
+            let mut b = a.clone();
+            b.push_str(" changed");
+            assert!(!SdsRc::ptr_eq(&a, &b));
+            assert_eq!(a.as_slice(), b"payload");
+            assert_eq!(b.as_slice(), b"payload changed");
+            assert_eq!(a.ref_count(), 1);
+            assert_eq!(b.ref_count(), 1);
+
+            // unique: changed in place, growing keeps the count
+            let p = b.as_slice().as_ptr();
+            b.make_mut().to_upper();
+            assert_eq!(b.as_slice().as_ptr(), p);
+            b.push_slice(&[b'x'; 1000]);
+            assert_eq!(b.len(), 1015);
+            assert_eq!(b.ref_count(), 1);
+            let c = b.clone();
+            assert_eq!(c.ref_count(), 2);
+            b.resize(4, false);
+            assert_eq!(b.as_slice(), b"PAYL");
+            assert_eq!(b.ref_count(), 1);
+            assert_eq!(c.len(), 1015);
+
+            let mut empty = SdsRc::empty();
+            let other = empty.clone();
+            empty.push_u8(b'!');
+            assert_eq!(empty.as_slice(), b"!");
+            assert!(other.is_empty());
+        }
+        check.assert_no_leaks();
+    }
+
+    #[test]
+    fn test_sds_rc_into_sds() {
+        let check = LeakCheck::new();
+        {
+            let a = SdsRc::from(Sds::from_str("plain"));
+            let b = a.clone();
+            let copied = b.into_sds();
+            assert_eq!(copied, Sds::from_str("plain"));
+            assert_ne!(copied.as_slice().as_ptr(), a.as_slice().as_ptr());
+
+            let p = a.as_slice().as_ptr();
+            let taken = a.into_sds();
+            assert_eq!(taken.as_slice().as_ptr(), p);
+            let back = SdsRc::from(taken);
+            assert_eq!(back.as_slice().as_ptr(), p);
+            assert_eq!(format!("{} {:?}", back, back), "plain \"plain\"");
+        }
+        check.assert_no_leaks();
+    }
+
+    #[test]
+    fn test_sds_rc_threads() {
+        let a = SdsRc::from_slice(&[b'z'; 100]);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let a = a.clone();
+                std::thread::spawn(move || {
+                    let clones: Vec<_> = (0..100).map(|_| a.clone()).collect();
+                    clones.iter().map(|s| s.len()).sum::<usize>()
+                })
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 100 * 100);
+        }
+        assert_eq!(a.ref_count(), 1);
+    }
+}
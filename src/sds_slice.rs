@@ -0,0 +1,252 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, RangeBounds};
+use std::str::Utf8Error;
+
+use crate::sds::{fmt_lossy, fmt_repr, Sds};
+use crate::util::{string2ld, string2ll, string2ull};
+
+/// A borrowed view of bytes, usually a range of an `Sds` made by
+/// `Sds::slice`, compared, hashed, shown and parsed the way an `Sds` is.
+///
+/// Arguments cut out of a query buffer can be looked at without allocating
+/// an `Sds` for each of them, `to_sds` makes the copy once one has to be
+/// kept.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use redis_rust_copy::{Sds, SdsSlice};
+///
+/// let mut db = HashMap::new();
+/// db.insert(Sds::from_str("key"), 1);
+///
+/// let query = Sds::from_str("GET key");
+/// let args: Vec<SdsSlice> = query.split(|&c| c == b' ').map(SdsSlice::new).collect();
+/// assert_eq!(args[0], "GET");
+/// assert_eq!(db.get(args[1].as_slice()), Some(&1));
+/// ```
+#[derive(Clone, Copy, Default)]
+pub struct SdsSlice<'a>(&'a [u8]);
+
+impl<'a> SdsSlice<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        SdsSlice(bytes)
+    }
+
+    pub fn as_slice(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// See `Sds::as_str`.
+    pub fn as_str(&self) -> Result<&'a str, Utf8Error> {
+        std::str::from_utf8(self.0)
+    }
+
+    /// See `Sds::to_str_lossy`.
+    pub fn to_str_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(self.0)
+    }
+
+    /// A narrower view of the same bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `range` is out of bounds, like slice indexing.
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> SdsSlice<'a> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        SdsSlice(&self.0[bounds])
+    }
+
+    /// The bytes copied in a new `Sds`.
+    pub fn to_sds(&self) -> Sds {
+        Sds::from_slice(self.0)
+    }
+
+    /// See `Sds::to_i64`.
+    pub fn to_i64(&self) -> Option<i64> {
+        string2ll(self.0)
+    }
+
+    /// See `Sds::to_u64`.
+    pub fn to_u64(&self) -> Option<u64> {
+        string2ull(self.0)
+    }
+
+    /// See `Sds::to_f64`.
+    pub fn to_f64(&self) -> Option<f64> {
+        string2ld(self.0)
+    }
+}
+
+impl Deref for SdsSlice<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl AsRef<[u8]> for SdsSlice<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<'a> From<&'a Sds> for SdsSlice<'a> {
+    fn from(sds: &'a Sds) -> Self {
+        SdsSlice(sds.as_slice())
+    }
+}
+
+impl<'a> From<&'a [u8]> for SdsSlice<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        SdsSlice(bytes)
+    }
+}
+
+impl<'a> From<&'a str> for SdsSlice<'a> {
+    fn from(s: &'a str) -> Self {
+        SdsSlice(s.as_bytes())
+    }
+}
+
+impl From<SdsSlice<'_>> for Sds {
+    fn from(slice: SdsSlice<'_>) -> Self {
+        slice.to_sds()
+    }
+}
+
+impl PartialEq for SdsSlice<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for SdsSlice<'_> {}
+
+impl PartialOrd for SdsSlice<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SdsSlice<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(other.0)
+    }
+}
+
+impl PartialEq<Sds> for SdsSlice<'_> {
+    fn eq(&self, other: &Sds) -> bool {
+        self.0 == other.as_slice()
+    }
+}
+
+impl PartialEq<SdsSlice<'_>> for Sds {
+    fn eq(&self, other: &SdsSlice<'_>) -> bool {
+        self.as_slice() == other.0
+    }
+}
+
+impl PartialEq<[u8]> for SdsSlice<'_> {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&[u8]> for SdsSlice<'_> {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<str> for SdsSlice<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for SdsSlice<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+// same hash as Sds and [u8], so a slice finds its Sds key in a map
+impl Hash for SdsSlice<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Display for SdsSlice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_lossy(self.0, f)
+    }
+}
+
+impl fmt::Debug for SdsSlice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_repr(self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_sds_slice() {
+        let sds = Sds::from_str("INCRBY counter -17 1.5e3");
+        let cmd = sds.slice(..6);
+        let key = sds.slice(7..14);
+        let incr = sds.slice(15..18);
+        let float = sds.slice(19..);
+        assert_eq!(cmd, "INCRBY");
+        assert_eq!(key, *b"counter".as_ref());
+        assert_eq!(key, Sds::from_str("counter"));
+        assert_eq!(Sds::from_str("counter"), key);
+        assert!(cmd < key);
+        assert_eq!(key.as_slice().as_ptr(), sds.as_slice()[7..].as_ptr());
+
+        assert_eq!(incr.to_i64(), Some(-17));
+        assert_eq!(incr.to_u64(), None);
+        assert_eq!(incr.slice(1..).to_u64(), Some(17));
+        assert_eq!(float.to_f64(), Some(1500.0));
+        assert_eq!(key.to_i64(), None);
+        assert_eq!(sds.slice(15..16).to_i64(), None);
+
+        assert_eq!(hash_of(&key), hash_of(&Sds::from_str("counter")));
+        assert_eq!(hash_of(&key), hash_of(b"counter".as_ref()));
+
+        let copy = key.to_sds();
+        assert_eq!(copy, key);
+        assert_ne!(copy.as_slice().as_ptr(), key.as_slice().as_ptr());
+        assert_eq!(Sds::from(cmd).as_slice(), b"INCRBY");
+
+        let bin = SdsSlice::new(b"a\xff\n");
+        assert_eq!(format!("{} {:?}", key, bin), "counter \"a\\xff\\n\"");
+        assert_eq!(bin.to_str_lossy(), "a\u{FFFD}\n");
+        assert_eq!(format!("[{:>4}]", bin.slice(..2)), "[  a\u{FFFD}]");
+        assert!(bin.as_str().is_err());
+        assert_eq!(sds.slice(..0).len(), 0);
+        assert_eq!(SdsSlice::default(), sds.slice(24..));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_sds_slice_out_of_bounds() {
+        Sds::from_str("abc").slice(2..4);
+    }
+}
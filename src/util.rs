@@ -0,0 +1,276 @@
+use std::convert::TryFrom;
+
+// same as
+// #define LONG_STR_SIZE 21
+/// Bytes needed by the longest i64 or u64 in decimal, sign included.
+pub const LONG_STR_SIZE: usize = 21;
+
+// same as
+// #define MAX_LONG_DOUBLE_CHARS 5*1024
+const MAX_LONG_DOUBLE_CHARS: usize = 5 * 1024;
+
+/// same as
+///
+/// uint32_t digits10(uint64_t v)
+///
+/// Number of decimal digits of `v`.
+pub fn digits10(v: u64) -> usize {
+    if v < 10 {
+        return 1;
+    }
+    if v < 100 {
+        return 2;
+    }
+    if v < 1000 {
+        return 3;
+    }
+    if v < 1_000_000_000_000 {
+        if v < 100_000_000 {
+            if v < 1_000_000 {
+                if v < 10_000 {
+                    return 4;
+                }
+                return 5 + (v >= 100_000) as usize;
+            }
+            return 7 + (v >= 10_000_000) as usize;
+        }
+        if v < 10_000_000_000 {
+            return 9 + (v >= 1_000_000_000) as usize;
+        }
+        return 11 + (v >= 100_000_000_000) as usize;
+    }
+    12 + digits10(v / 1_000_000_000_000)
+}
+
+/// same as
+///
+/// int ull2str(char *dst, size_t dstlen, unsigned long long value)
+///
+/// Writes `value` in decimal at the start of `dst`, two digits at a time,
+/// and returns the length written, or None if `dst` is too small. Unlike
+/// the C version no '\0' is added.
+pub fn ull2str(dst: &mut [u8], mut value: u64) -> Option<usize> {
+    const DIGITS: &[u8; 200] = b"0001020304050607080910111213141516171819\
+                                 2021222324252627282930313233343536373839\
+                                 4041424344454647484950515253545556575859\
+                                 6061626364656667686970717273747576777879\
+                                 8081828384858687888990919293949596979899";
+
+    let length = digits10(value);
+    if length > dst.len() {
+        return None;
+    }
+
+    let mut next = length - 1;
+    while value >= 100 {
+        let i = (value % 100) as usize * 2;
+        value /= 100;
+        dst[next] = DIGITS[i + 1];
+        dst[next - 1] = DIGITS[i];
+        next -= 2;
+    }
+
+    if value < 10 {
+        dst[next] = b'0' + value as u8;
+    } else {
+        let i = value as usize * 2;
+        dst[next] = DIGITS[i + 1];
+        dst[next - 1] = DIGITS[i];
+    }
+    Some(length)
+}
+
+/// same as
+///
+/// int ll2str(char *dst, size_t dstlen, long long svalue)
+///
+/// `ull2str` with a leading '-' for negative values.
+pub fn ll2str(dst: &mut [u8], value: i64) -> Option<usize> {
+    if value >= 0 {
+        return ull2str(dst, value as u64);
+    }
+
+    let (sign, digits) = dst.split_first_mut()?;
+    *sign = b'-';
+    ull2str(digits, value.unsigned_abs()).map(|len| len + 1)
+}
+
+// the digits of a strict decimal number: no sign, no leading zero unless it
+// is "0" itself, no overflow
+fn string2digits(s: &[u8]) -> Option<u64> {
+    match s {
+        [] => None,
+        [b'0'] => Some(0),
+        [b'1'..=b'9', ..] => s.iter().try_fold(0u64, |v, &c| {
+            if !c.is_ascii_digit() {
+                return None;
+            }
+            v.checked_mul(10)?.checked_add((c - b'0') as u64)
+        }),
+        _ => None,
+    }
+}
+
+/// same as
+///
+/// int string2ll(const char *s, size_t slen, long long *value)
+///
+/// Parses `s` only if it is exactly how `ll2str` writes an i64: no spaces,
+/// no '+', no leading zeros, no "-0" and no overflow. This is what makes
+/// "10" an integer but "010" or " 10" plain strings in Redis.
+///
+/// # Examples
+///
+/// ```
+/// use redis_rust_copy::string2ll;
+///
+/// assert_eq!(string2ll(b"-123"), Some(-123));
+/// assert_eq!(string2ll(b"+123"), None);
+/// assert_eq!(string2ll(b"0123"), None);
+/// assert_eq!(string2ll(b"9223372036854775808"), None);
+/// ```
+pub fn string2ll(s: &[u8]) -> Option<i64> {
+    if s.len() >= LONG_STR_SIZE {
+        return None;
+    }
+
+    match s {
+        [b'-', digits @ ..] => {
+            if digits == b"0" {
+                return None;
+            }
+            let v = string2digits(digits)?;
+            if v > i64::MIN.unsigned_abs() {
+                return None;
+            }
+            Some((v as i64).wrapping_neg())
+        }
+        _ => i64::try_from(string2digits(s)?).ok(),
+    }
+}
+
+/// same as
+///
+/// int string2ull(const char *s, unsigned long long *value)
+///
+/// `string2ll` for u64, with the same strict rules and no sign at all.
+pub fn string2ull(s: &[u8]) -> Option<u64> {
+    if s.len() >= LONG_STR_SIZE {
+        return None;
+    }
+    string2digits(s)
+}
+
+/// same as
+///
+/// int string2ld(const char *s, size_t slen, long double *dp)
+///
+/// Parses a float, f64 standing for long double. Leading spaces, trailing
+/// garbage, nan and values too big or too small for f64 are refused, "inf"
+/// is fine.
+pub fn string2ld(s: &[u8]) -> Option<f64> {
+    if s.is_empty() || s.len() >= MAX_LONG_DOUBLE_CHARS {
+        return None;
+    }
+
+    let text = std::str::from_utf8(s).ok()?;
+    let value: f64 = text.parse().ok()?;
+    if value.is_nan() {
+        return None;
+    }
+
+    // out of range: strtold sets ERANGE where parse silently saturates
+    let mantissa = text.split(['e', 'E']).next().unwrap_or(text);
+    let is_inf_literal = mantissa.bytes().any(|c| c.is_ascii_alphabetic());
+    if value.is_infinite() && !is_inf_literal {
+        return None;
+    }
+    if value == 0.0 && mantissa.bytes().any(|c| (b'1'..=b'9').contains(&c)) {
+        return None;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_digits10() {
+        let mut v = 1u64;
+        for digits in 1..20 {
+            assert_eq!(digits10(v - 1), (digits - 1).max(1));
+            assert_eq!(digits10(v), digits);
+            v *= 10;
+        }
+        assert_eq!(digits10(u64::MAX), 20);
+    }
+
+    #[test]
+    fn test_ll2str() {
+        let mut buf = [0u8; LONG_STR_SIZE];
+        for v in [0, 9, 10, 99, 100, 101, -1, -10, -100, 12345, i64::MAX, i64::MIN] {
+            let len = ll2str(&mut buf, v).unwrap();
+            assert_eq!(&buf[..len], v.to_string().as_bytes());
+        }
+        for v in [0, 7, 42, 1000, u64::MAX] {
+            let len = ull2str(&mut buf, v).unwrap();
+            assert_eq!(&buf[..len], v.to_string().as_bytes());
+        }
+
+        assert_eq!(ull2str(&mut buf[..2], 100), None);
+        assert_eq!(ull2str(&mut buf[..3], 100), Some(3));
+        assert_eq!(ll2str(&mut buf[..3], -100), None);
+        assert_eq!(ll2str(&mut [], 0), None);
+    }
+
+    #[test]
+    fn test_string2ll() {
+        assert_eq!(string2ll(b""), None);
+        assert_eq!(string2ll(b" 1"), None);
+        assert_eq!(string2ll(b"1 "), None);
+        assert_eq!(string2ll(b"01"), None);
+        assert_eq!(string2ll(b"+1"), None);
+        assert_eq!(string2ll(b"-"), None);
+        assert_eq!(string2ll(b"-0"), None);
+        assert_eq!(string2ll(b"-01"), None);
+        assert_eq!(string2ll(b"1a"), None);
+        assert_eq!(string2ll(b"0"), Some(0));
+        assert_eq!(string2ll(b"1"), Some(1));
+        assert_eq!(string2ll(b"-1"), Some(-1));
+        assert_eq!(string2ll(b"99"), Some(99));
+        assert_eq!(string2ll(b"-99"), Some(-99));
+        assert_eq!(string2ll(b"-9223372036854775808"), Some(i64::MIN));
+        assert_eq!(string2ll(b"-9223372036854775809"), None);
+        assert_eq!(string2ll(b"9223372036854775807"), Some(i64::MAX));
+        assert_eq!(string2ll(b"9223372036854775808"), None);
+        assert_eq!(string2ll(b"18446744073709551616"), None);
+
+        assert_eq!(string2ull(b"18446744073709551615"), Some(u64::MAX));
+        assert_eq!(string2ull(b"18446744073709551616"), None);
+        assert_eq!(string2ull(b"0"), Some(0));
+        assert_eq!(string2ull(b"-1"), None);
+        assert_eq!(string2ull(b"+1"), None);
+        assert_eq!(string2ull(b"00"), None);
+    }
+
+    #[test]
+    fn test_string2ld() {
+        assert_eq!(string2ld(b"1.5"), Some(1.5));
+        assert_eq!(string2ld(b"-1e3"), Some(-1000.0));
+        assert_eq!(string2ld(b"+.5"), Some(0.5));
+        assert_eq!(string2ld(b"007"), Some(7.0));
+        assert_eq!(string2ld(b"0"), Some(0.0));
+        assert_eq!(string2ld(b"0e10"), Some(0.0));
+        assert_eq!(string2ld(b"inf"), Some(f64::INFINITY));
+        assert_eq!(string2ld(b"-inf"), Some(f64::NEG_INFINITY));
+        assert_eq!(string2ld(b""), None);
+        assert_eq!(string2ld(b" 1"), None);
+        assert_eq!(string2ld(b"1 "), None);
+        assert_eq!(string2ld(b"1x"), None);
+        assert_eq!(string2ld(b"nan"), None);
+        assert_eq!(string2ld(b"1e400"), None);
+        assert_eq!(string2ld(b"1e-400"), None);
+        assert_eq!(string2ld(&[b'1'; MAX_LONG_DOUBLE_CHARS]), None);
+    }
+}
@@ -1,95 +1,725 @@
+use std::alloc::{GlobalAlloc, Layout};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 use std::mem::size_of;
+#[cfg(target_os = "linux")]
+use std::os::raw::{c_int, c_long};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
 
-extern "C" {
-    fn malloc(size: usize) -> *const u8;
-    fn free(ptr: *const u8);
-    fn realloc(ptr: *const u8, size: usize) -> *const u8;
+use crate::allocator::{Backend, ZAllocator, MALLOC_MIN_ALIGN};
+
+/// name of the allocator backend picked at build time, same as
+///
+/// #define ZMALLOC_LIB
+pub const Z_MALLOC_LIB: &str = Backend::NAME;
+
+// same as
+// #define HAVE_MALLOC_SIZE
+const HAVE_MALLOC_SIZE: bool = Backend::HAVE_MALLOC_SIZE && !cfg!(feature = "prefix-size");
+
+// same as
+// #define PREFIX_SIZE
+// size of the header keeping the requested size in front of every block
+// without HAVE_MALLOC_SIZE, kept at MALLOC_MIN_ALIGN so blocks stay aligned
+const PREFIX_SIZE: usize = if HAVE_MALLOC_SIZE { 0 } else { MALLOC_MIN_ALIGN };
+
+/// same as
+///
+/// size_t zmalloc_size(void *ptr)
+///
+/// Bytes the block takes from the allocator, the PREFIX_SIZE header included.
+/// This is what `used_memory()` counts.
+///
+/// # Safety
+///
+/// `ptr` must come from the z_malloc functions and not be freed yet.
+#[inline]
+pub unsafe fn z_malloc_size(ptr: *const u8) -> usize {
+    if HAVE_MALLOC_SIZE {
+        Backend::usable_size(ptr)
+    } else {
+        *(ptr.sub(PREFIX_SIZE) as *const usize) + PREFIX_SIZE
+    }
 }
 
-#[cfg(target_os = "macos")]
-extern "C" {
-    fn malloc_size(ptr: *const u8) -> usize;
+/// same as
+///
+/// size_t zmalloc_usable_size(void *ptr)
+///
+/// Bytes of the block the caller may use.
+///
+/// # Safety
+///
+/// `ptr` must come from the z_malloc functions and not be freed yet.
+#[inline]
+pub unsafe fn z_malloc_usable_size(ptr: *const u8) -> usize {
+    z_malloc_size(ptr) - PREFIX_SIZE
+}
+
+// the block of the backend behind ptr
+#[inline]
+unsafe fn real_ptr(ptr: *const u8) -> *const u8 {
+    ptr.sub(PREFIX_SIZE)
+}
+
+// writes the prefix of a block from the backend and returns the pointer
+// handed out for it
+#[inline]
+unsafe fn user_ptr(real: *const u8, size: usize) -> *const u8 {
+    if !HAVE_MALLOC_SIZE {
+        *(real as *mut usize) = size;
+    }
+    real.add(PREFIX_SIZE)
+}
+
+// same as
+// static redisAtomic size_t used_memory
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+// same as
+// server.stat_peak_memory, but kept up to date on every allocation
+static PEAK_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+// same as
+// #define update_zmalloc_stat_alloc(__n) atomicIncr(used_memory,(__n))
+#[inline]
+fn update_stat_alloc(size: usize) {
+    let used = USED_MEMORY.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_MEMORY.fetch_max(used, Ordering::Relaxed);
+}
+
+// same as
+// #define update_zmalloc_stat_free(__n) atomicDecr(used_memory,(__n))
+#[inline]
+fn update_stat_free(size: usize) {
+    USED_MEMORY.fetch_sub(size, Ordering::Relaxed);
 }
 
-#[cfg(target_os = "macos")]
+/// same as
+///
+/// size_t zmalloc_used_memory(void)
+///
+/// Bytes currently held by blocks from the z_malloc functions, counted by
+/// their usable size rather than the requested one.
 #[inline]
-unsafe fn z_malloc_size(ptr: *const u8) -> usize {
-    malloc_size(ptr)
+pub fn used_memory() -> usize {
+    USED_MEMORY.load(Ordering::Relaxed)
+}
+
+/// The highest `used_memory()` seen since start or the last
+/// `reset_used_memory_peak()`, like used_memory_peak of INFO memory.
+#[inline]
+pub fn used_memory_peak() -> usize {
+    PEAK_MEMORY.load(Ordering::Relaxed)
+}
+
+/// Starts the peak over from the current used memory, as CONFIG RESETSTAT does.
+pub fn reset_used_memory_peak() {
+    PEAK_MEMORY.store(used_memory(), Ordering::Relaxed);
 }
 
+/// same as
+///
+/// int jemalloc_purge(void)
+///
+/// Asks the allocator backend to give its free pages back to the system,
+/// what MEMORY PURGE does. Returns if any memory was released, always false
+/// for backends that cannot.
+pub fn z_malloc_purge() -> bool {
+    Backend::purge()
+}
 
 #[cfg(target_os = "linux")]
 extern "C" {
-    fn malloc_usable_size(ptr: *const u8) -> usize;
+    fn sysconf(name: c_int) -> c_long;
+}
+
+#[cfg(target_os = "linux")]
+const SC_PAGESIZE: c_int = 30;
+
+/// same as
+///
+/// size_t zmalloc_get_rss(void)
+///
+/// Resident set size of the process in bytes, read from the rss field of
+/// `/proc/self/stat`. 0 if it cannot be read.
+#[cfg(target_os = "linux")]
+pub fn z_malloc_get_rss() -> usize {
+    let page = unsafe { sysconf(SC_PAGESIZE) };
+    let stat = match std::fs::read_to_string("/proc/self/stat") {
+        Ok(stat) => stat,
+        Err(_) => return 0,
+    };
+
+    // the command name in field 2 may contain spaces, so count after its ')'
+    // rss is field 24 and the one right after ')' is field 3
+    stat.rfind(')')
+        .and_then(|pos| stat[pos + 1..].split_ascii_whitespace().nth(24 - 3))
+        .and_then(|rss| rss.parse::<usize>().ok())
+        .map_or(0, |rss| rss * page.max(0) as usize)
+}
+
+/// same as
+///
+/// size_t zmalloc_get_rss(void)
+///
+/// No way to get the rss of the process here, so return the memory usage
+/// estimated by z_malloc.
+#[cfg(not(target_os = "linux"))]
+pub fn z_malloc_get_rss() -> usize {
+    used_memory()
 }
 
+/// same as
+///
+/// size_t zmalloc_get_smap_bytes_by_field(char *field, long pid)
+///
+/// Sums the `field` lines (like "Private_Dirty:") of `/proc/<pid>/smaps` in
+/// bytes, pid `None` for this process. 0 if it cannot be read.
 #[cfg(target_os = "linux")]
-unsafe fn z_malloc_size(ptr: *const u8) -> usize {
-    malloc_usable_size(ptr)
+pub fn z_malloc_get_smap_bytes_by_field(field: &str, pid: Option<u32>) -> usize {
+    let path = match pid {
+        Some(pid) => format!("/proc/{}/smaps", pid),
+        None => "/proc/self/smaps".to_string(),
+    };
+    let smaps = match std::fs::read_to_string(path) {
+        Ok(smaps) => smaps,
+        Err(_) => return 0,
+    };
+
+    smaps.lines()
+        .filter_map(|line| line.strip_prefix(field))
+        .filter_map(|value| value.trim().strip_suffix("kB"))
+        .filter_map(|kb| kb.trim().parse::<usize>().ok())
+        .map(|kb| kb * 1024)
+        .sum()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn z_malloc_get_smap_bytes_by_field(_field: &str, _pid: Option<u32>) -> usize {
+    0
+}
+
+/// same as
+///
+/// size_t zmalloc_get_private_dirty(long pid)
+#[inline]
+pub fn z_malloc_get_private_dirty(pid: Option<u32>) -> usize {
+    z_malloc_get_smap_bytes_by_field("Private_Dirty:", pid)
+}
+
+/// same as
+///
+/// float zmalloc_get_fragmentation_ratio(size_t rss)
+///
+/// How many bytes of `rss` back every byte counted by `used_memory()`, 0 if
+/// nothing is allocated yet.
+pub fn z_malloc_get_fragmentation_ratio(rss: usize) -> f32 {
+    let used = used_memory();
+    if used == 0 {
+        return 0.0;
+    }
+    rss as f32 / used as f32
+}
+
+/// The error of the fallible `try_*` allocations, reported instead of
+/// calling the oom handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    size: usize,
+}
+
+impl AllocError {
+    #[inline]
+    pub(crate) fn new(size: usize) -> Self {
+        Self { size }
+    }
+
+    /// the requested size in bytes
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Display for AllocError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "memory allocation of {} bytes failed", self.size)
+    }
+}
+
+impl Error for AllocError {}
+
+// same as
+// static void zmalloc_default_oom(size_t size)
+fn z_malloc_default_oom(size: usize) -> ! {
+    eprintln!("z_malloc: Out of memory trying to allocate {} bytes", size);
+    std::process::abort();
+}
+
+// same as
+// static void (*zmalloc_oom_handler)(size_t) = zmalloc_default_oom;
+static OOM_HANDLER: RwLock<fn(usize) -> !> = RwLock::new(z_malloc_default_oom);
+
+/// same as
+///
+/// void zmalloc_set_oom_handler(void (*oom_handler)(size_t))
+///
+/// The handler is called with the requested size when an allocation that is
+/// not allowed to fail does. The default one logs to stderr and aborts.
+///
+/// Returns the previous handler, so it can be put back.
+pub fn z_malloc_set_oom_handler(handler: fn(usize) -> !) -> fn(usize) -> ! {
+    let mut current = OOM_HANDLER.write().unwrap_or_else(|e| e.into_inner());
+    std::mem::replace(&mut *current, handler)
+}
+
+// call the oom handler for a failed allocation of size bytes
+pub(crate) fn z_malloc_oom(size: usize) -> ! {
+    let handler = *OOM_HANDLER.read().unwrap_or_else(|e| e.into_inner());
+    handler(size)
+}
+
+// unit tests changing process wide allocator settings, the oom handler or
+// the backtrace tracking, hold this so they do not run at the same time
+#[cfg(test)]
+pub(crate) static GLOBAL_STATE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+// installs an oom handler for a unit test, the previous one is back and the
+// lock released when dropped
+#[cfg(test)]
+pub(crate) struct OomHandlerGuard {
+    previous: fn(usize) -> !,
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl OomHandlerGuard {
+    pub(crate) fn new(handler: fn(usize) -> !) -> Self {
+        let lock = GLOBAL_STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        OomHandlerGuard {
+            previous: z_malloc_set_oom_handler(handler),
+            _lock: lock,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for OomHandlerGuard {
+    fn drop(&mut self) {
+        z_malloc_set_oom_handler(self.previous);
+    }
+}
+
+#[cfg(any(test, feature = "alloc-track"))]
+use crate::alloc_track::{track_alloc, track_free, track_restore, track_take};
+
+#[cfg(not(any(test, feature = "alloc-track")))]
+#[inline(always)]
+fn track_alloc(_ptr: *const u8, _size: usize) {}
+
+#[cfg(not(any(test, feature = "alloc-track")))]
+#[inline(always)]
+fn track_free(_ptr: *const u8) {}
+
+#[cfg(not(any(test, feature = "alloc-track")))]
+enum TakenRecord {}
+
+#[cfg(not(any(test, feature = "alloc-track")))]
+#[inline(always)]
+fn track_take(_ptr: *const u8) -> Option<TakenRecord> {
+    None
+}
+
+#[cfg(not(any(test, feature = "alloc-track")))]
+#[inline(always)]
+fn track_restore(_ptr: *const u8, _taken: Option<TakenRecord>) {}
+
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Clone, Copy)]
+enum FaultMode {
+    Off,
+    // fail when the countdown reaches 1
+    Nth(usize),
+    // xorshift64* state, fail when the next number is below threshold
+    Random { state: u64, threshold: u64 },
+}
+
+// per thread, so tests running side by side do not fail each other
+#[cfg(any(test, feature = "fault-injection"))]
+thread_local! {
+    static FAULT_MODE: std::cell::Cell<FaultMode> = const { std::cell::Cell::new(FaultMode::Off) };
+}
+
+/// Makes the `n`th allocation from now on the current thread fail, 1 for the
+/// next one. Only that one fails, the mode is off afterwards.
+///
+/// Every allocation of z_malloc counts, including `z_malloc_usable`,
+/// `z_realloc_usable` and `z_malloc_of_type`, which then call the oom handler.
+#[cfg(any(test, feature = "fault-injection"))]
+pub fn z_malloc_fail_nth(n: usize) {
+    assert!(n > 0, "allocations are counted from 1");
+    FAULT_MODE.with(|mode| mode.set(FaultMode::Nth(n)));
+}
+
+/// Makes every allocation on the current thread fail with `probability`,
+/// drawn from a generator seeded with `seed` so a run can be replayed.
+#[cfg(any(test, feature = "fault-injection"))]
+pub fn z_malloc_fail_random(seed: u64, probability: f64) {
+    let threshold = (probability.clamp(0.0, 1.0) * u64::MAX as f64) as u64;
+    // xorshift gets stuck on 0
+    let state = if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed };
+    FAULT_MODE.with(|mode| mode.set(FaultMode::Random { state, threshold }));
+}
+
+/// Stops injecting allocation failures on the current thread.
+#[cfg(any(test, feature = "fault-injection"))]
+pub fn z_malloc_fail_off() {
+    FAULT_MODE.with(|mode| mode.set(FaultMode::Off));
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+fn fault_injected() -> bool {
+    FAULT_MODE.with(|mode| match mode.get() {
+        FaultMode::Off => false,
+        FaultMode::Nth(1) => {
+            mode.set(FaultMode::Off);
+            true
+        }
+        FaultMode::Nth(n) => {
+            mode.set(FaultMode::Nth(n - 1));
+            false
+        }
+        FaultMode::Random { mut state, threshold } => {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            mode.set(FaultMode::Random { state, threshold });
+            state.wrapping_mul(0x2545_f491_4f6c_dd1d) < threshold
+        }
+    })
+}
+
+#[cfg(not(any(test, feature = "fault-injection")))]
+#[inline(always)]
+fn fault_injected() -> bool {
+    false
 }
 
-#[cfg(any(target_os = "macos", target_os = "linux"))]
+// same as
+// void *ztrymalloc_usable(size_t size, size_t *usable)
+// returns null on failure
 pub fn z_try_malloc_usable(size: usize) -> (*const u8, usize) {
+    if fault_injected() {
+        return (std::ptr::null(), 0);
+    }
+    let real_size = match size.checked_add(PREFIX_SIZE) {
+        Some(real_size) => real_size,
+        None => return (std::ptr::null(), 0),
+    };
     unsafe {
-        let p = malloc(size);
+        let p = Backend::malloc(real_size);
         if p.is_null() {
             (p, 0)
         } else {
-            (p, z_malloc_size(p))
+            let p = user_ptr(p, size);
+            update_stat_alloc(z_malloc_size(p));
+            let usable = z_malloc_usable_size(p);
+            track_alloc(p, usable);
+            (p, usable)
         }
     }
 }
 
+// same as
+// void *zmalloc_usable(size_t size, size_t *usable)
+// never returns null, calls the oom handler on failure
 #[inline]
 pub fn z_malloc_usable(size: usize) -> (*const u8, usize) {
-    z_try_malloc_usable(size)
+    let (p, usable) = z_try_malloc_usable(size);
+    if p.is_null() {
+        z_malloc_oom(size);
+    }
+    (p, usable)
+}
+
+// same as
+// void *ztrycalloc_usable(size_t size, size_t *usable)
+// returns null on failure
+pub fn z_try_calloc_usable(size: usize) -> (*const u8, usize) {
+    let (p, usable) = z_try_malloc_usable(size);
+    if !p.is_null() {
+        unsafe { (p as *mut u8).write_bytes(0, usable); }
+    }
+    (p, usable)
+}
+
+/// same as
+///
+/// void *zcalloc(size_t size)
+///
+/// Zeroed block, never null, calls the oom handler on failure.
+///
+/// # Safety
+///
+/// The returned block must be released with `z_free`.
+#[inline]
+pub unsafe fn z_calloc(size: usize) -> *const u8 {
+    let (p, _) = z_try_calloc_usable(size);
+    if p.is_null() {
+        z_malloc_oom(size);
+    }
+    p
 }
 
 #[test]
-#[cfg(any(target_os = "macos", target_os = "linux"))]
 fn test_z_malloc_size() {
     unsafe {
         let (p, len) = z_try_malloc_usable(9);
         let pp = p as *mut u8;
         *pp = 31;
-        assert_eq!(len, z_malloc_size(p));
-        free(p)
+        assert!(len >= 9);
+        assert_eq!(len, z_malloc_usable_size(p));
+        assert_eq!(len + PREFIX_SIZE, z_malloc_size(p));
+        assert_eq!(p as usize % MALLOC_MIN_ALIGN, 0);
+        // used_memory drops by this z_malloc_size, checked by the integration
+        // test where no other test allocates at the same time
+        assert_eq!(z_free_usable(p), len);
+
+        let p = z_calloc(100);
+        assert!((0..100).all(|i| *p.add(i) == 0));
+        z_free(p);
     }
 }
 
-#[cfg(not(any(target_os = "macos", target_os = "linux")))]
-pub fn z_try_malloc_usable(size: usize) -> (*const u8, usize) {
+#[test]
+fn test_fault_injection() {
     unsafe {
-        let p = malloc(size);
-        if p.is_null() {
-            (p, 0)
-        } else {
-            (p, size)
-        }
+        z_malloc_fail_nth(2);
+        let (p, _) = z_try_malloc_usable(8);
+        assert!(!p.is_null());
+        let (q, usable) = z_try_realloc_usable(p, 64);
+        assert!(q.is_null());
+        assert_eq!(usable, 0);
+        // only the nth fails
+        let (p, _) = z_try_realloc_usable(p, 64);
+        assert!(!p.is_null());
+        z_free(p);
+
+        z_malloc_fail_nth(1);
+        assert!(z_try_malloc_of_type::<u64>().is_null());
+
+        // realloc of null is a single allocation
+        z_malloc_fail_nth(2);
+        let (p, _) = z_try_realloc_usable(std::ptr::null(), 16);
+        assert!(!p.is_null());
+        let (q, _) = z_try_realloc_usable(std::ptr::null(), 16);
+        assert!(q.is_null());
+        z_free(p);
+
+        let run = |seed| {
+            z_malloc_fail_random(seed, 0.5);
+            let fails: Vec<bool> = (0..64).map(|_| {
+                let (p, _) = z_try_malloc_usable(8);
+                z_free(p);
+                p.is_null()
+            }).collect();
+            z_malloc_fail_off();
+            fails
+        };
+        let fails = run(42);
+        assert_eq!(fails, run(42));
+        assert!(fails.iter().any(|&f| f) && fails.iter().any(|&f| !f));
+
+        z_malloc_fail_random(7, 0.0);
+        let (p, _) = z_try_malloc_usable(8);
+        assert!(!p.is_null());
+        z_free(p);
+        z_malloc_fail_off();
     }
 }
 
+/// # Safety
+///
+/// `ptr` must be null or come from the z_malloc functions and not be freed yet.
 #[inline]
 pub unsafe fn z_free(ptr: *const u8) {
-    free(ptr);
+    z_free_usable(ptr);
+}
+
+/// same as
+///
+/// void zfree_usable(void *ptr, size_t *usable)
+///
+/// Frees the block and returns its usable size, 0 for null.
+///
+/// # Safety
+///
+/// `ptr` must be null or come from the z_malloc functions and not be freed yet.
+pub unsafe fn z_free_usable(ptr: *const u8) -> usize {
+    if ptr.is_null() {
+        return 0;
+    }
+    let size = z_malloc_size(ptr);
+    update_stat_free(size);
+    track_free(ptr);
+    Backend::free(real_ptr(ptr));
+    size - PREFIX_SIZE
 }
 
+// same as
+// void *zmalloc(size_t size)
+// never returns null, calls the oom handler on failure
+/// # Safety
+///
+/// The returned block is uninitialized and must be released with `z_free`.
 #[inline]
 pub unsafe fn z_malloc(size: usize) -> *const u8 {
-    malloc(size)
+    z_malloc_usable(size).0
 }
 
+/// # Safety
+///
+/// The returned block is uninitialized and must be released with `z_free`.
 #[inline]
 pub unsafe fn z_malloc_of_type<T>() -> *const T {
     z_malloc(size_of::<T>()) as *const T
 }
 
+// returns null on failure
+/// # Safety
+///
+/// The returned block is uninitialized and must be released with `z_free`.
 #[inline]
-pub unsafe fn z_realloc_usable(ptr: *const u8, size: usize) -> (*const u8, usize) {
-    let ptr = realloc(ptr, size);
+pub unsafe fn z_try_malloc_of_type<T>() -> *const T {
+    z_try_malloc_usable(size_of::<T>()).0 as *const T
+}
+
+// same as
+// void *ztryrealloc_usable(void *ptr, size_t size, size_t *usable)
+// returns null and keeps ptr untouched on failure
+/// # Safety
+///
+/// `ptr` must be null or come from the z_malloc functions and not be freed yet.
+#[inline]
+pub unsafe fn z_try_realloc_usable(ptr: *const u8, size: usize) -> (*const u8, usize) {
+    // counted once, by z_try_malloc_usable
+    if ptr.is_null() {
+        return z_try_malloc_usable(size);
+    }
+    if fault_injected() {
+        return (std::ptr::null(), 0);
+    }
+    let real_size = match size.checked_add(PREFIX_SIZE) {
+        Some(real_size) => real_size,
+        None => return (std::ptr::null(), 0),
+    };
+    let old_size = z_malloc_size(ptr);
+    let old_ptr = ptr;
+    // out of the live blocks before realloc may free it, as z_free_usable
+    // does, or another thread could get the address and lose its record
+    let taken = track_take(old_ptr);
+    let ptr = Backend::realloc(real_ptr(ptr), real_size);
     if ptr.is_null() {
+        track_restore(old_ptr, taken);
         (ptr, 0)
     } else {
-        (ptr, z_malloc_size(ptr))
+        let ptr = user_ptr(ptr, size);
+        update_stat_free(old_size);
+        update_stat_alloc(z_malloc_size(ptr));
+        let usable = z_malloc_usable_size(ptr);
+        track_alloc(ptr, usable);
+        (ptr, usable)
+    }
+}
+
+// same as
+// void *zrealloc_usable(void *ptr, size_t size, size_t *usable)
+// never returns null, calls the oom handler on failure
+/// # Safety
+///
+/// `ptr` must be null or come from the z_malloc functions and not be freed yet.
+#[inline]
+pub unsafe fn z_realloc_usable(ptr: *const u8, size: usize) -> (*const u8, usize) {
+    let (new_ptr, usable) = z_try_realloc_usable(ptr, size);
+    if new_ptr.is_null() {
+        z_malloc_oom(size);
+    }
+    (new_ptr, usable)
+}
+
+// backends only promise MALLOC_MIN_ALIGN, so over allocate and keep the
+// block pointer right in front of the aligned one for z_free_aligned
+unsafe fn z_malloc_aligned(align: usize, size: usize) -> *const u8 {
+    let p = z_try_malloc_usable(size + align + size_of::<usize>()).0;
+    if p.is_null() {
+        return p;
+    }
+    let aligned = (p as usize + size_of::<usize>() + align - 1) & !(align - 1);
+    let aligned = p.add(aligned - p as usize);
+    *(aligned as *mut *const u8).sub(1) = p;
+    aligned
+}
+
+unsafe fn z_free_aligned(ptr: *const u8) {
+    z_free(*(ptr as *const *const u8).sub(1));
+}
+
+/// Global allocator over the z_malloc functions, so the memory held by
+/// `Vec`, `String`, `Box` and friends is counted by `used_memory()` as well.
+///
+/// # Examples
+///
+/// ```
+/// use redis_rust_copy::{used_memory, ZMalloc};
+///
+/// #[global_allocator]
+/// static GLOBAL: ZMalloc = ZMalloc;
+///
+/// let v: Vec<u8> = Vec::with_capacity(4096);
+/// assert!(used_memory() >= v.capacity());
+/// ```
+pub struct ZMalloc;
+
+impl ZMalloc {
+    #[inline]
+    fn use_malloc(align: usize) -> bool {
+        align <= MALLOC_MIN_ALIGN
+    }
+}
+
+unsafe impl GlobalAlloc for ZMalloc {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // std reports the failure by itself, so never reach the oom handler here
+        if Self::use_malloc(layout.align()) {
+            z_try_malloc_usable(layout.size()).0 as *mut u8
+        } else {
+            z_malloc_aligned(layout.align(), layout.size()) as *mut u8
+        }
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if Self::use_malloc(layout.align()) {
+            z_free(ptr);
+        } else {
+            z_free_aligned(ptr);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if Self::use_malloc(layout.align()) {
+            return z_try_realloc_usable(ptr, new_size).0 as *mut u8;
+        }
+
+        // realloc cannot keep an over aligned block aligned, move it by hand
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr.copy_to_nonoverlapping(new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
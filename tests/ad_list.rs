@@ -24,10 +24,10 @@ fn test_basic() {
     assert_eq!(elements.as_slice(), &[1, 2]);
 
     unsafe {
-        assert_eq!((*list.get(0)).value, 1);
-        assert_eq!((*list.get(-1)).value, 2);
-        assert!(list.get(2).is_null());
-        assert!(list.get(-3).is_null());
+        assert_eq!((*list.index(0)).value, 1);
+        assert_eq!((*list.index(-1)).value, 2);
+        assert!(list.index(2).is_null());
+        assert!(list.index(-3).is_null());
     }
 
     list.move_head_to_tail();
@@ -90,4 +90,64 @@ fn test_custom() {
             assert_eq!((*n).value, p);
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_try_push() {
+    let mut list = List::new();
+    list.try_push_back(2).unwrap().try_push_front(1).unwrap();
+    list.try_push_back(3).unwrap();
+    let elements: Vec<_> = list.iter()
+        .map(|n| unsafe{(*n).value})
+        .collect();
+    assert_eq!(elements.as_slice(), &[1, 2, 3]);
+}
+
+#[test]
+fn test_safe_api() {
+    let mut list = List::new();
+    assert_eq!(list.front(), None);
+    assert_eq!(list.back(), None);
+    assert_eq!(list.get(0), None);
+    assert_eq!(list.pop_front(), None);
+    assert_eq!(list.pop_back(), None);
+
+    for v in 1..=5 {
+        list.push_back(v);
+    }
+    assert_eq!(list.front(), Some(&1));
+    assert_eq!(list.back(), Some(&5));
+    for i in 0..5 {
+        assert_eq!(list.get(i), Some(&(i as i32 + 1)));
+        assert_eq!(list.get(-i - 1), Some(&(5 - i as i32)));
+    }
+    assert_eq!(list.get(5), None);
+    assert_eq!(list.get(-6), None);
+    assert_eq!(list.get(isize::MIN), None);
+
+    *list.get_mut(2).unwrap() = 30;
+    *list.get_mut(-1).unwrap() += 10;
+    assert_eq!(list.get_mut(9), None);
+    assert_eq!(list.find(&30), Some(2));
+    assert_eq!(list.find(&15), Some(4));
+    assert_eq!(list.find(&3), None);
+
+    assert_eq!(list.pop_front(), Some(1));
+    assert_eq!(list.pop_back(), Some(15));
+    assert_eq!(list.len(), 3);
+    assert_eq!(list.front(), Some(&2));
+    assert_eq!(list.back(), Some(&4));
+    assert_eq!(list.pop_back(), Some(4));
+    assert_eq!(list.pop_back(), Some(30));
+    assert_eq!(list.pop_front(), Some(2));
+    assert!(list.is_empty());
+    assert_eq!(list.front(), None);
+
+    list.push_front(7);
+    assert_eq!(list.back(), Some(&7));
+
+    let mut names = List::new();
+    names.set_value_equals_method(Some(|a: &str, b: &str| a.eq_ignore_ascii_case(b)));
+    names.push_back("foo").push_back("Bar");
+    assert_eq!(names.find(&"bar"), Some(1));
+}
@@ -0,0 +1,35 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+use redis_rust_copy::{used_memory, ZMalloc};
+
+#[global_allocator]
+static GLOBAL: ZMalloc = ZMalloc;
+
+#[test]
+fn test_global_alloc() {
+    let size = 1 << 20;
+    let mut v: Vec<u8> = Vec::with_capacity(size);
+    assert!(used_memory() >= size);
+
+    v.extend_from_slice(&[1u8; 16]);
+    v.reserve(size * 2);
+    assert!(used_memory() >= size * 2);
+
+    let before = used_memory();
+    drop(v);
+    assert!(used_memory() < before);
+
+    unsafe {
+        let layout = Layout::from_size_align(100, 4096).unwrap();
+        let p = GLOBAL.alloc(layout);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % 4096, 0);
+        p.write_bytes(7, 100);
+
+        let p = GLOBAL.realloc(p, layout, 10000);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % 4096, 0);
+        assert_eq!(*p.add(99), 7);
+        GLOBAL.dealloc(p, Layout::from_size_align(10000, 4096).unwrap());
+    }
+}
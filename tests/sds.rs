@@ -8,13 +8,13 @@ fn test_sds() {
 
     let s = "get/set";
     let mut sds = Sds::from_str(s);
-    assert_eq!(sds.as_str_uncheck(), s);
+    assert_eq!(sds.as_str().unwrap(), s);
     assert_ne!(sds, empty1);
     assert!(sds > empty1);
     assert!(sds.starts_with("get".as_bytes()));
 
     sds.make_ascii_uppercase();
-    assert_eq!(sds.as_str_uncheck(), &s.to_ascii_uppercase());
+    assert_eq!(sds.as_str().unwrap(), &s.to_ascii_uppercase());
 
     let s = [1u8, 2, 4, 6];
     let mut sds = Sds::from_slice(&s);
@@ -25,7 +25,7 @@ fn test_sds() {
     sds.clear();
     assert_eq!(sds, empty1);
 
-    let len = u16::max_value();
+    let len = u16::MAX;
     let batch_size = 1024;
     let batch = [9u8].repeat(batch_size);
     let mut total = 0;
@@ -41,4 +41,91 @@ fn test_sds() {
 
     assert_eq!(sds.len(), total);
     assert_eq!(sds.iter().fold(0u64, |per, item| per + (*item as u64)), total as u64 * 9);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_try_alloc() {
+    let mut sds = Sds::try_from_slice(b"try").unwrap();
+    sds.try_push_slice(b" alloc").unwrap().try_push_slice(b"!").unwrap();
+    assert_eq!(sds.as_slice(), b"try alloc!");
+
+    sds.try_reserve(100).unwrap();
+    assert!(sds.alloc() - sds.len() >= 100);
+
+    let alloc = sds.alloc();
+    let err = sds.try_reserve(1 << 60).unwrap_err();
+    assert!(err.size() > 1 << 60);
+    assert_eq!(sds.as_slice(), b"try alloc!");
+    assert_eq!(sds.alloc(), alloc);
+}
+
+#[test]
+fn test_try_reserve_overflow() {
+    // whatever the backend, sizes past isize::MAX fail instead of wrapping
+    let mut sds = Sds::from_str("overflow");
+    for additional in [usize::MAX, usize::MAX - 20, isize::MAX as usize - 8] {
+        assert!(sds.try_reserve_exact(additional).is_err(), "{}", additional);
+        assert!(sds.try_reserve(additional).is_err(), "{}", additional);
+        assert_eq!(sds.as_slice(), b"overflow");
+        assert!(sds.alloc() < 1 << 20);
+        assert!(sds.spare_capacity_mut().len() < 1 << 20);
+    }
+}
+
+#[test]
+fn test_write_fmt() {
+    use std::fmt::Write;
+
+    let mut sds = Sds::from_str("*");
+    let value = "foo";
+    write!(sds, "{}\r\n${}\r\n{}", 1, value.len(), value).unwrap();
+    assert_eq!(sds.as_slice(), b"*1\r\n$3\r\nfoo");
+
+    let long = "x".repeat(1000);
+    writeln!(sds, "{}", long).unwrap();
+    assert_eq!(sds.len(), 11 + 1001);
+    assert!(sds.ends_with(b"x\n"));
+}
+
+#[test]
+fn test_std_traits() {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    let mut map = HashMap::new();
+    map.insert(Sds::from("key"), 1);
+    map.insert(Sds::from(&b"bin\xff"[..]), 2);
+    assert_eq!(map.get(&b"key"[..]), Some(&1));
+    assert_eq!(map.get(&b"bin\xff"[..]), Some(&2));
+    assert_eq!(map.get(&Sds::from(String::from("key"))), Some(&1));
+
+    let mut sds: Sds = (b'a'..=b'e').collect();
+    assert_eq!(sds, "abcde");
+    assert_eq!(sds, *"abcde");
+    assert_eq!(sds, &b"abcde"[..]);
+    assert_eq!(sds, *b"abcde".as_ref());
+    sds.extend(vec![b'f'; 300]);
+    assert_eq!(sds.len(), 305);
+    assert_eq!(Sds::from(vec![1u8, 2]).as_ref(), &[1, 2]);
+    assert_eq!(Sds::default(), Sds::empty());
+
+    let mut out = Sds::empty();
+    let kind = "io";
+    write!(out, "{}:{}", kind, 1).unwrap();
+    out.write_all(b"\0\xff").unwrap();
+    out.flush().unwrap();
+    assert_eq!(out, &b"io:1\0\xff"[..]);
+
+    let moved = std::thread::spawn(move || {
+        out.push_str("!");
+        out
+    }).join().unwrap();
+    assert_eq!(moved, &b"io:1\0\xff!"[..]);
+
+    let shared = std::sync::Arc::new(Sds::from("shared"));
+    let reader = {
+        let shared = shared.clone();
+        std::thread::spawn(move || shared.len())
+    };
+    assert_eq!(reader.join().unwrap(), shared.len());
+}
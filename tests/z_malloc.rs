@@ -0,0 +1,136 @@
+use std::sync::Mutex;
+
+use redis_rust_copy::{List, Sds, IntSet};
+use redis_rust_copy::{used_memory, z_malloc_usable, z_realloc_usable, z_free};
+use redis_rust_copy::{z_malloc_size, z_malloc_usable_size, z_free_usable, z_calloc};
+use redis_rust_copy::{z_malloc_set_oom_handler, z_try_malloc_usable};
+use redis_rust_copy::{z_malloc_get_rss, z_malloc_get_private_dirty, z_malloc_get_smap_bytes_by_field};
+use redis_rust_copy::z_malloc_get_fragmentation_ratio;
+use redis_rust_copy::{used_memory_peak, reset_used_memory_peak, z_malloc_purge, Z_MALLOC_LIB};
+use redis_rust_copy::{memory_doctor, MemoryStats};
+
+// used memory is global, tests checking it must not run at the same time
+static SERIAL: Mutex<()> = Mutex::new(());
+
+#[test]
+fn test_used_memory() {
+    let _serial = SERIAL.lock().unwrap();
+    let base = used_memory();
+
+    let (p, usable) = z_malloc_usable(100);
+    assert!(usable >= 100);
+    unsafe {
+        assert_eq!(usable, z_malloc_usable_size(p));
+        assert!(z_malloc_size(p) >= usable);
+        assert_eq!(used_memory(), base + z_malloc_size(p));
+    }
+
+    let (p, usable) = unsafe { z_realloc_usable(p, 1000) };
+    assert!(usable >= 1000);
+    unsafe { assert_eq!(used_memory(), base + z_malloc_size(p)); }
+
+    // the prefix, if any, is counted as well
+    let (size, before) = unsafe { (z_malloc_size(p), used_memory()) };
+    assert_eq!(unsafe { z_free_usable(p) }, usable);
+    assert_eq!(before - used_memory(), size);
+    assert_eq!(used_memory(), base);
+
+    let p = unsafe { z_calloc(64) };
+    unsafe {
+        assert_eq!(std::slice::from_raw_parts(p, 64), &[0u8; 64][..]);
+        z_free(p);
+    }
+    assert_eq!(used_memory(), base);
+
+    {
+        let mut sds = Sds::from_str("used memory");
+        sds.push_slice(&[7u8; 300]);
+        let mut list = List::new();
+        list.push_back(1).push_front(2);
+        let mut set = IntSet::new();
+        set.insert(i64::MAX);
+        set.insert(1);
+        assert!(used_memory() > base + sds.alloc());
+    }
+    assert_eq!(used_memory(), base);
+}
+
+fn panic_oom(size: usize) -> ! {
+    panic!("oom {}", size)
+}
+
+// installs panic_oom, the previous handler is back when dropped
+struct PanicOomGuard(fn(usize) -> !);
+
+impl PanicOomGuard {
+    fn new() -> Self {
+        PanicOomGuard(z_malloc_set_oom_handler(panic_oom))
+    }
+}
+
+impl Drop for PanicOomGuard {
+    fn drop(&mut self) {
+        z_malloc_set_oom_handler(self.0);
+    }
+}
+
+#[test]
+fn test_oom_handler() {
+    let _serial = SERIAL.lock().unwrap();
+    let huge = 1usize << 60;
+    let (p, usable) = z_try_malloc_usable(huge);
+    assert!(p.is_null());
+    assert_eq!(usable, 0);
+
+    {
+        let _oom = PanicOomGuard::new();
+        let err = std::panic::catch_unwind(|| z_malloc_usable(huge)).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), &format!("oom {}", huge));
+
+        let (p, _) = z_malloc_usable(16);
+        let err = std::panic::catch_unwind(|| unsafe { z_realloc_usable(p, huge) }).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), &format!("oom {}", huge));
+        // a failed realloc leaves the block alone
+        unsafe { z_free(p); }
+    }
+
+    // the guard put the default handler back
+    let previous = z_malloc_set_oom_handler(panic_oom);
+    z_malloc_set_oom_handler(previous);
+    assert!(!std::ptr::fn_addr_eq(previous, panic_oom as fn(usize) -> !));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_process_stats() {
+    let _serial = SERIAL.lock().unwrap();
+    let sds = Sds::from_slice(&[1u8; 1 << 20]);
+    let rss = z_malloc_get_rss();
+    assert!(rss >= sds.len());
+    assert!(z_malloc_get_private_dirty(None) >= sds.len());
+    assert!(z_malloc_get_private_dirty(Some(std::process::id())) > 0);
+    assert_eq!(z_malloc_get_smap_bytes_by_field("No_Such_Field:", None), 0);
+    assert!(z_malloc_get_fragmentation_ratio(rss) > 0.0);
+}
+
+#[test]
+fn test_peak_and_purge() {
+    let _serial = SERIAL.lock().unwrap();
+    reset_used_memory_peak();
+    let base = used_memory();
+    assert_eq!(used_memory_peak(), base);
+
+    let (p, _) = z_malloc_usable(1 << 20);
+    let size = unsafe { z_malloc_size(p) };
+    unsafe { z_free(p); }
+    assert_eq!(used_memory(), base);
+    assert!(used_memory_peak() >= base + size);
+
+    z_malloc_purge();
+    let stats = MemoryStats::current();
+    assert!(stats.peak >= stats.used);
+    assert!(memory_doctor().contains(Z_MALLOC_LIB));
+
+    reset_used_memory_peak();
+    assert_eq!(used_memory_peak(), used_memory());
+}